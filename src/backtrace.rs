@@ -0,0 +1,34 @@
+use std::fmt;
+
+/// One place on the call stack when a runtime error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktraceFrame {
+    /// The word that was running, or None for interactive code
+    pub word: Option<String>,
+    /// The address of the opcode that failed, or of the call for the frames further out
+    pub address: usize,
+    /// The source token that opcode was compiled from, if it is known
+    pub token: Option<String>,
+}
+
+/// The Forth words that were running when a runtime error happened, innermost first
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for frame in self.frames.iter() {
+            match &frame.word {
+                Some(word) => write!(f, "  in {}", word)?,
+                None => write!(f, "  in interactive code")?,
+            }
+            match &frame.token {
+                Some(token) => writeln!(f, " at {} (address {})", token, frame.address)?,
+                None => writeln!(f, " at address {}", frame.address)?,
+            }
+        }
+        Ok(())
+    }
+}
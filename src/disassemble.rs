@@ -0,0 +1,71 @@
+use crate::syscall::{SYSCALL_ENTRY, Syscall};
+use crate::{ForthCompiler, ForthError};
+use rust_simple_stack_processor::Opcode;
+
+impl ForthCompiler {
+    /// A listing of the opcodes a word was compiled to, one line for each with its address.
+    /// Calls and jumps to words show the name of the word, syscalls the name of the syscall,
+    /// and relative jumps the address they go to
+    pub fn disassemble(&self, word_name: &str) -> Result<String, ForthError> {
+        let Some(index) = self.current_entry(word_name) else {
+            return Err(if self.intrinsic_words.contains_key(word_name) {
+                ForthError::InvalidSyntax(format!(
+                    "{} is built in, so there is no compiled code to show",
+                    word_name
+                ))
+            } else {
+                ForthError::UnknownToken(word_name.to_owned())
+            });
+        };
+        let entry = &self.dictionary[index];
+        let opcodes = &self.sm.st.opcodes[entry.start..entry.end()];
+
+        let mut listing = format!(": {}\n", word_name);
+        let mut i = 0;
+        while i < opcodes.len() {
+            let address = entry.start + i;
+            let (length, text) = self.describe(&opcodes[i..], address);
+            listing.push_str(&format!("{:6}  {}\n", address, text));
+            i += length;
+        }
+        listing.push_str(";\n");
+        Ok(listing)
+    }
+
+    // How many opcodes go on the next line of a listing, and what it says
+    fn describe(&self, opcodes: &[Opcode], address: usize) -> (usize, String) {
+        match opcodes {
+            [Opcode::LDI(id), Opcode::LDI(entry), Opcode::CALL, ..]
+                if *entry == SYSCALL_ENTRY as i64 && Syscall::from_id(*id).is_some() =>
+            {
+                let syscall = Syscall::from_id(*id).unwrap();
+                (3, format!("SYSCALL {:?}", syscall))
+            }
+            [
+                Opcode::LDI(target),
+                opcode @ (Opcode::CALL | Opcode::JMP),
+                ..,
+            ] => (2, format!("{:?} {}", opcode, self.name_at(*target))),
+            // The jump is relative to the opcode after the LDI
+            [
+                Opcode::LDI(offset),
+                opcode @ (Opcode::JR | Opcode::JRZ | Opcode::JRNZ),
+                ..,
+            ] => (
+                2,
+                format!("{:?} -> {}", opcode, address as i64 + 1 + offset),
+            ),
+            [Opcode::LDI(value), ..] => (1, format!("LDI {}", value)),
+            [opcode, ..] => (1, format!("{:?}", opcode)),
+            [] => unreachable!("There is always an opcode left to describe"),
+        }
+    }
+
+    // The word whose code starts at address, or just the address if there isn't one
+    fn name_at(&self, address: i64) -> String {
+        self.dictionary
+            .iter()
+            .find(|entry| entry.start as i64 == address)
+            .map_or_else(|| address.to_string(), |entry| entry.name.clone())
+    }
+}
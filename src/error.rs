@@ -1,4 +1,5 @@
-use rust_simple_stack_processor::{GasLimit, StackMachineError};
+use crate::stack_effect::StackEffect;
+use rust_simple_stack_processor::{GasLimit, Opcode, StackMachineError};
 use thiserror::Error;
 
 /// This Enum lists the errors that the Forth Interpreter might return
@@ -30,6 +31,55 @@ pub enum ForthError {
     RanOutOfGas { gas_used: u64, gas_limit: GasLimit },
     #[error("Internal numeric overflow")]
     InternalNumericOverflow,
+    #[error("Division by zero in {opcode:?}{}", at_address(.address))]
+    DivisionByZero {
+        opcode: Opcode,
+        address: Option<usize>,
+    },
+    #[error("Numeric overflow in {opcode:?}{}", at_address(.address))]
+    NumericOverflow {
+        opcode: Opcode,
+        address: Option<usize>,
+    },
+    #[error("Output error: {0}")]
+    OutputError(#[from] std::io::Error),
+    #[error("Word was declared but never defined: {0}")]
+    UndefinedWord(String),
+    #[error("{word} at token {position} is never closed")]
+    UnterminatedControlStructure { word: String, position: usize },
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+    #[error("{word} is declared {declared}, but {}", actual_effect(.actual))]
+    StackEffectMismatch {
+        word: String,
+        declared: StackEffect,
+        // None if it depends on which way the code goes
+        actual: Option<StackEffect>,
+    },
+}
+
+// The address is only known when the error comes out of running compiled code
+fn at_address(address: &Option<usize>) -> String {
+    address.map_or_else(String::new, |address| format!(" at address {}", address))
+}
+
+fn actual_effect(actual: &Option<StackEffect>) -> String {
+    match actual {
+        Some(actual) => format!("its code does {}", actual),
+        None => "what its code leaves depends on the path it takes".to_owned(),
+    }
+}
+
+impl ForthError {
+    // Fill in the address of the opcode that failed, for the errors that have one
+    pub(crate) fn at_address(mut self, pc: usize) -> ForthError {
+        if let ForthError::DivisionByZero { address, .. }
+        | ForthError::NumericOverflow { address, .. } = &mut self
+        {
+            *address = Some(pc);
+        }
+        self
+    }
 }
 
 /// Convert StackMachineError to a ForthError so our Interpreter functions can
@@ -52,12 +102,14 @@ impl From<StackMachineError> for ForthError {
                 gas_used,
                 gas_limit,
             },
-            StackMachineError::NumericOverflow { failing_opcode: _ } => {
-                ForthError::InternalNumericOverflow
-            }
-            StackMachineError::DivisionByZero { failing_opcode: _ } => {
-                ForthError::InternalNumericOverflow
-            }
+            StackMachineError::NumericOverflow { failing_opcode } => ForthError::NumericOverflow {
+                opcode: failing_opcode,
+                address: None,
+            },
+            StackMachineError::DivisionByZero { failing_opcode } => ForthError::DivisionByZero {
+                opcode: failing_opcode,
+                address: None,
+            },
             StackMachineError::TryFromIntError(_) => ForthError::InternalNumericOverflow,
         }
     }
@@ -85,6 +137,26 @@ impl From<ForthError> for i32 {
                 gas_limit: _,
             } => 11,
             ForthError::InternalNumericOverflow => 12,
+            ForthError::DivisionByZero {
+                opcode: _,
+                address: _,
+            } => 18,
+            ForthError::NumericOverflow {
+                opcode: _,
+                address: _,
+            } => 19,
+            ForthError::InvalidImage(_) => 20,
+            ForthError::StackEffectMismatch {
+                word: _,
+                declared: _,
+                actual: _,
+            } => 21,
+            ForthError::OutputError(_) => 15,
+            ForthError::UndefinedWord(_) => 16,
+            ForthError::UnterminatedControlStructure {
+                word: _,
+                position: _,
+            } => 17,
         }
     }
 }
@@ -0,0 +1,435 @@
+use crate::syscall::syscall_entry;
+use crate::{
+    DictionaryEntry, FIRST_WORD_ADDRESS, ForthCompiler, ForthError, StackEffect, WordKind,
+};
+use rust_simple_stack_processor::{GasLimit, Opcode, StackMachine};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+// Images start with these, and are refused if the version isn't the one we write
+const IMAGE_MAGIC: &[u8; 4] = b"RFCI";
+const IMAGE_VERSION: u32 = 4;
+
+// Opcodes are written as their position in this list, followed by the value for LDI
+const OPCODES: [Opcode; 39] = [
+    Opcode::JMP,
+    Opcode::JR,
+    Opcode::JRZ,
+    Opcode::JRNZ,
+    Opcode::CALL,
+    Opcode::CMPZ,
+    Opcode::CMPNZ,
+    Opcode::LDI(0),
+    Opcode::DROP,
+    Opcode::SWAP,
+    Opcode::SWAP2,
+    Opcode::RET,
+    Opcode::ADD,
+    Opcode::SUB,
+    Opcode::MUL,
+    Opcode::DIV,
+    Opcode::NOT,
+    Opcode::DUP,
+    Opcode::DUP2,
+    Opcode::TRAP,
+    Opcode::NOP,
+    Opcode::PUSHLP,
+    Opcode::INCLP,
+    Opcode::ADDLP,
+    Opcode::GETLP,
+    Opcode::GETLP2,
+    Opcode::DROPLP,
+    Opcode::CMPLOOP,
+    Opcode::OVER2,
+    Opcode::GtR,
+    Opcode::RGt,
+    Opcode::RAt,
+    Opcode::GtR2,
+    Opcode::RGt2,
+    Opcode::RAt2,
+    Opcode::AND,
+    Opcode::NEWCELLS,
+    Opcode::MOVETOCELLS,
+    Opcode::MOVEFROMCELLS,
+];
+
+impl ForthCompiler {
+    /// Write the compiled words and variable storage out, so load_image can pick up from here
+    /// without compiling the source again. The number stack isn't saved.
+    pub fn save_image(&mut self, writer: impl Write) -> Result<(), ForthError> {
+        let cells = self.read_cells()?;
+        let mut w = ImageWriter(writer);
+
+        w.bytes(IMAGE_MAGIC)?;
+        w.u32(IMAGE_VERSION)?;
+
+        w.usize(self.last_function)?;
+        for opcode in self.sm.st.opcodes[..self.last_function].iter() {
+            w.opcode(opcode)?;
+        }
+
+        w.usize(self.cells_reserved)?;
+        w.usize(cells.len())?;
+        for cell in cells {
+            w.i64(cell)?;
+        }
+
+        w.usize(self.dictionary.len())?;
+        for entry in self.dictionary.iter() {
+            w.string(&entry.name)?;
+            w.usize(entry.start)?;
+            w.usize(entry.length)?;
+            w.u8(match entry.kind {
+                WordKind::Word => 0,
+                WordKind::Marker => 1,
+                WordKind::Forward => 2,
+            })?;
+            w.u8(u8::from(entry.execution_token_taken)
+                | u8::from(entry.inline) << 1
+                | u8::from(entry.stack_effect.is_some()) << 2)?;
+            if let Some(effect) = entry.stack_effect {
+                w.usize(effect.inputs)?;
+                w.usize(effect.outputs)?;
+            }
+            w.usize(entry.source_map.len())?;
+            for token in entry.source_map.iter() {
+                match token {
+                    Some(token) => {
+                        w.u8(1)?;
+                        w.string(token)?;
+                    }
+                    None => w.u8(0)?,
+                }
+            }
+        }
+
+        w.addresses(&self.word_addresses)?;
+        w.addresses(&self.value_addresses)?;
+        w.addresses(&self.deferred_slots)?;
+
+        w.0.flush()?;
+        Ok(())
+    }
+
+    /// Replace every compiled word and all variable storage with what save_image wrote.
+    /// Trap handlers, the output and the number stack are kept.
+    pub fn load_image(&mut self, reader: impl Read) -> Result<(), ForthError> {
+        let mut r = ImageReader(reader);
+
+        if &r.array::<4>()? != IMAGE_MAGIC {
+            return Err(invalid("not a compiled image"));
+        }
+        let version = r.u32()?;
+        if version != IMAGE_VERSION {
+            return Err(invalid(&format!(
+                "version {} is not supported, only {}",
+                version, IMAGE_VERSION
+            )));
+        }
+
+        let last_function = r.usize()?;
+        if last_function < FIRST_WORD_ADDRESS {
+            return Err(invalid("the opcodes end before the first word could start"));
+        }
+        let opcodes = (0..last_function)
+            .map(|_| r.opcode())
+            .collect::<Result<Vec<_>, _>>()?;
+        // Every syscall goes through here, so without it running anything would go wrong
+        if !opcodes.starts_with(&syscall_entry()) {
+            return Err(invalid("the syscall entry point is missing"));
+        }
+
+        let cells_reserved = r.usize()?;
+        let cell_count = r.usize()?;
+        if cell_count > cells_reserved {
+            return Err(invalid("more cells than were reserved"));
+        }
+        let cells = (0..cell_count)
+            .map(|_| r.i64())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let entry_count = r.usize()?;
+        let mut dictionary = Vec::new();
+        for _ in 0..entry_count {
+            let name = r.string()?;
+            let start = r.usize()?;
+            let length = r.usize()?;
+            let kind = match r.u8()? {
+                0 => WordKind::Word,
+                1 => WordKind::Marker,
+                2 => WordKind::Forward,
+                _ => return Err(invalid("unknown kind of word")),
+            };
+            let flags = r.u8()?;
+            let stack_effect = if flags & 4 != 0 {
+                Some(StackEffect {
+                    inputs: r.usize()?,
+                    outputs: r.usize()?,
+                })
+            } else {
+                None
+            };
+            let source_count = r.usize()?;
+            let source_map = (0..source_count)
+                .map(|_| match r.u8()? {
+                    0 => Ok(None),
+                    _ => r.string().map(Some),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if start < FIRST_WORD_ADDRESS
+                || start
+                    .checked_add(length)
+                    .is_none_or(|end| end > last_function)
+            {
+                return Err(invalid(&format!("{} is outside of the words", name)));
+            }
+            dictionary.push(DictionaryEntry {
+                name,
+                start,
+                length,
+                kind,
+                execution_token_taken: flags & 1 != 0,
+                inline: flags & 2 != 0,
+                stack_effect,
+                source_map,
+            });
+        }
+
+        // Each word reserves at most one cell, and the next run allocates the ones that aren't
+        // yet, so a count no dictionary could have reserved would run out of memory there
+        if cells_reserved - cell_count > dictionary.len() {
+            return Err(invalid(
+                "more cells were reserved than the words could have",
+            ));
+        }
+
+        let word_addresses = r.addresses()?;
+        let value_addresses = r.addresses()?;
+        let deferred_slots = r.addresses()?;
+        check_layout(&dictionary, &word_addresses)?;
+        // Cells that were reserved but not allocated yet are allocated by the next run, before
+        // anything can use them
+        for (name, &address) in value_addresses.iter().chain(deferred_slots.iter()) {
+            if address >= cells_reserved {
+                return Err(invalid(&format!(
+                    "the cell for {} is outside of the cells",
+                    name
+                )));
+            }
+        }
+
+        // Cells can't be taken away from a StackMachine, so start again with a new one
+        let mut sm = StackMachine::default();
+        sm.trap_handlers = std::mem::take(&mut self.sm.trap_handlers);
+        sm.st.number_stack = std::mem::take(&mut self.sm.st.number_stack);
+        sm.st.opcodes = opcodes;
+        self.sm = sm;
+
+        self.last_function = last_function;
+        self.dictionary = dictionary;
+        self.word_addresses = word_addresses;
+        self.value_addresses = value_addresses;
+        self.deferred_slots = deferred_slots;
+        self.cells_reserved = cells_reserved;
+        self.cells_allocated = cells.len();
+        self.interactive_source.clear();
+        self.last_backtrace = None;
+        self.write_cells(&cells)?;
+
+        #[cfg(feature = "enable_reflection")]
+        {
+            self.word_definitions.clear();
+            self.word_opcodes = self
+                .word_addresses
+                .iter()
+                .filter_map(|(name, &start)| {
+                    let index = self.current_entry(name)?;
+                    let end = self.dictionary[index].end();
+                    Some((name.clone(), self.sm.st.opcodes[start..end].to_vec()))
+                })
+                .collect();
+        }
+        Ok(())
+    }
+
+    // Runs a few opcodes in the space after the compiled words
+    fn run_scratch_opcodes(&mut self, opcodes: Vec<Opcode>) -> Result<(), ForthError> {
+        self.sm.st.opcodes.truncate(self.last_function);
+        self.sm.st.opcodes.extend(opcodes);
+        self.sm.st.opcodes.push(Opcode::RET);
+        let result = self.sm.execute(self.last_function, GasLimit::Unlimited);
+        self.sm.st.opcodes.truncate(self.last_function);
+        Ok(result?)
+    }
+
+    // The StackMachine only lets us at its cells through MOVEFROMCELLS
+    fn read_cells(&mut self) -> Result<Vec<i64>, ForthError> {
+        let count = self.cells_allocated;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        self.run_scratch_opcodes(vec![
+            Opcode::LDI(0),
+            Opcode::LDI(count as i64),
+            Opcode::MOVEFROMCELLS,
+        ])?;
+        // They come out with the first cell on top of the stack
+        let number_stack = &mut self.sm.st.number_stack;
+        let mut cells = number_stack.split_off(number_stack.len() - count);
+        cells.reverse();
+        Ok(cells)
+    }
+
+    fn write_cells(&mut self, cells: &[i64]) -> Result<(), ForthError> {
+        if cells.is_empty() {
+            return Ok(());
+        }
+        let count = Opcode::LDI(cells.len() as i64);
+        let mut opcodes = vec![count.clone(), Opcode::NEWCELLS];
+        // MOVETOCELLS puts the top of the stack in the first cell
+        opcodes.extend(cells.iter().rev().map(|&cell| Opcode::LDI(cell)));
+        opcodes.extend([Opcode::LDI(0), count, Opcode::MOVETOCELLS]);
+        self.run_scratch_opcodes(opcodes)
+    }
+}
+
+// Words mustn't share memory, finding free memory and reclaiming it both count on that. Each name
+// has to lead to the start of a word with that name
+fn check_layout(
+    dictionary: &[DictionaryEntry],
+    word_addresses: &HashMap<String, usize>,
+) -> Result<(), ForthError> {
+    let mut entries: Vec<&DictionaryEntry> = dictionary.iter().collect();
+    entries.sort_by_key(|entry| entry.start);
+    for pair in entries.windows(2) {
+        if pair[1].start < pair[0].end() {
+            return Err(invalid(&format!(
+                "{} and {} overlap",
+                pair[0].name, pair[1].name
+            )));
+        }
+    }
+
+    for (name, &start) in word_addresses.iter() {
+        if !dictionary
+            .iter()
+            .any(|entry| entry.start == start && &entry.name == name)
+        {
+            return Err(invalid(&format!("{} doesn't lead to a word", name)));
+        }
+    }
+    Ok(())
+}
+
+fn invalid(reason: &str) -> ForthError {
+    ForthError::InvalidImage(reason.to_owned())
+}
+
+// Everything is little endian, lengths and addresses are written as u64
+struct ImageWriter<W: Write>(W);
+
+impl<W: Write> ImageWriter<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.bytes(&[value])
+    }
+
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn i64(&mut self, value: i64) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn usize(&mut self, value: usize) -> io::Result<()> {
+        self.bytes(&(value as u64).to_le_bytes())
+    }
+
+    fn string(&mut self, value: &str) -> io::Result<()> {
+        self.usize(value.len())?;
+        self.bytes(value.as_bytes())
+    }
+
+    fn opcode(&mut self, opcode: &Opcode) -> io::Result<()> {
+        let tag = OPCODES
+            .iter()
+            .position(|o| std::mem::discriminant(o) == std::mem::discriminant(opcode))
+            .expect("every opcode is in OPCODES");
+        self.u8(tag as u8)?;
+        if let Opcode::LDI(value) = opcode {
+            self.i64(*value)?;
+        }
+        Ok(())
+    }
+
+    fn addresses(&mut self, addresses: &HashMap<String, usize>) -> io::Result<()> {
+        self.usize(addresses.len())?;
+        for (name, &address) in addresses.iter() {
+            self.string(name)?;
+            self.usize(address)?;
+        }
+        Ok(())
+    }
+}
+
+struct ImageReader<R: Read>(R);
+
+impl<R: Read> ImageReader<R> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ForthError> {
+        let mut bytes = [0; N];
+        self.0
+            .read_exact(&mut bytes)
+            .map_err(|err| invalid(&err.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ForthError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ForthError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, ForthError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, ForthError> {
+        usize::try_from(u64::from_le_bytes(self.array()?))
+            .map_err(|_| invalid("a length is too big for this machine"))
+    }
+
+    fn string(&mut self) -> Result<String, ForthError> {
+        let length = self.usize()?;
+        let mut bytes = Vec::new();
+        (&mut self.0)
+            .take(length as u64)
+            .read_to_end(&mut bytes)
+            .map_err(|err| invalid(&err.to_string()))?;
+        if bytes.len() != length {
+            return Err(invalid("it ends part way through a name"));
+        }
+        String::from_utf8(bytes).map_err(|_| invalid("a name isn't valid UTF-8"))
+    }
+
+    fn opcode(&mut self) -> Result<Opcode, ForthError> {
+        let tag = usize::from(self.u8()?);
+        match OPCODES.get(tag) {
+            Some(Opcode::LDI(_)) => Ok(Opcode::LDI(self.i64()?)),
+            Some(opcode) => Ok(opcode.clone()),
+            None => Err(invalid(&format!("unknown opcode {}", tag))),
+        }
+    }
+
+    fn addresses(&mut self) -> Result<HashMap<String, usize>, ForthError> {
+        let count = self.usize()?;
+        (0..count)
+            .map(|_| Ok((self.string()?, self.usize()?)))
+            .collect()
+    }
+}
@@ -1,12 +1,37 @@
 use rust_forth_tokenizer::{ForthToken, ForthTokenizer};
 pub use rust_simple_stack_processor::GasLimit;
-use rust_simple_stack_processor::{Opcode, StackMachine};
+use rust_simple_stack_processor::{Opcode, StackMachine, StackMachineError};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
+use std::io::{self, Write};
+use std::rc::Rc;
 
 mod error;
 pub use error::ForthError;
 
+mod backtrace;
+pub use backtrace::{Backtrace, BacktraceFrame};
+
+mod disassemble;
+
+mod image;
+
+mod optimizer;
+
+mod source;
+pub use source::SourceLocation;
+
+mod stack_effect;
+use stack_effect::Analysis;
+pub use stack_effect::StackEffect;
+
+mod syscall;
+use syscall::{SYSCALL_ENTRY, SYSCALL_RESUME, Syscall, SyscallTrapHandler};
+
+// Compiled words go after the syscall entry point at the bottom of memory
+const FIRST_WORD_ADDRESS: usize = SYSCALL_RESUME + 1;
+
 #[cfg(test)]
 mod tests;
 
@@ -25,18 +50,60 @@ pub struct ForthCompiler {
     // This is the location in memory that points to the location after the last compiled opcode
     // So its an ideal place to run interactive compiled opcodes
     last_function: usize,
+    // Every block of compiled word code in memory, in the order they were defined. A redefined
+    // word keeps its old entry here for as long as other compiled code might still call it
+    dictionary: Vec<DictionaryEntry>,
     // Remember the definition for words
     #[cfg(feature = "enable_reflection")]
     pub word_definitions: HashMap<String, String>,
     // Remember the opcodes for words
     #[cfg(feature = "enable_reflection")]
     pub word_opcodes: HashMap<String, Vec<Opcode>>,
+    // Names given storage (VARIABLE, CONSTANT, VALUE) each own a StackMachine cell, this is how
+    // many cells have been handed out, and how many of them have actually been created so far
+    cells_reserved: usize,
+    cells_allocated: usize,
+    // The cell behind each VALUE, so TO knows where to store
+    value_addresses: HashMap<String, usize>,
+    // The cell holding the word each DEFER word runs, so IS knows where to store
+    deferred_slots: HashMap<String, usize>,
+    // Where ., EMIT, ." and friends send their output
+    output: Box<dyn Write>,
+    // The word compile_tokens_as_word is working on, if any
+    word_being_compiled: Option<WordBeingCompiled>,
+    // Set by the syscall trap handler when compiled code asks the compiler to do something for it
+    pending_syscall: Rc<Cell<Option<Syscall>>>,
+    // The source token behind each opcode of the interactive code
+    interactive_source: Vec<Option<String>>,
+    // Where the last runtime error happened
+    last_backtrace: Option<Backtrace>,
+    // Where the last compile error happened
+    last_error_location: Option<SourceLocation>,
+    // Whether compiled code goes through the peephole optimizer
+    optimize: bool,
+    // Whether definitions are checked against their ( before -- after ) comments
+    check_stack_effects: bool,
+    // How many opcodes the last run got through
+    gas_used: u64,
+    // Whether runs go one opcode at a time, so a failure can say where it happened
+    backtraces: bool,
+    // Words this many opcodes long or shorter (not counting the RET) are copied into their callers
+    inline_threshold: usize,
 }
 
 impl Default for ForthCompiler {
     fn default() -> ForthCompiler {
+        let pending_syscall = Rc::new(Cell::new(None));
+        let mut sm = StackMachine::default();
+        // The syscall entry point sits at the bottom of memory, compiled words go after it
+        sm.st.opcodes = syscall::syscall_entry();
+        sm.trap_handlers
+            .push(Box::new(SyscallTrapHandler::new(Rc::clone(
+                &pending_syscall,
+            ))));
+
         ForthCompiler {
-            sm: StackMachine::default(),
+            sm,
             intrinsic_words: HashMap::from([
                 ("SWAP", vec![Opcode::SWAP]),
                 ("NOT", vec![Opcode::NOT]),
@@ -51,6 +118,47 @@ impl Default for ForthCompiler {
                 ("2DROP", vec![Opcode::DROP, Opcode::DROP]),
                 ("2OVER", vec![Opcode::OVER2]),
                 ("2SWAP", vec![Opcode::SWAP2]),
+                (
+                    "OVER",
+                    vec![Opcode::GtR, Opcode::DUP, Opcode::RGt, Opcode::SWAP],
+                ),
+                (
+                    "ROT",
+                    vec![Opcode::GtR, Opcode::SWAP, Opcode::RGt, Opcode::SWAP],
+                ),
+                (
+                    "-ROT",
+                    vec![Opcode::SWAP, Opcode::GtR, Opcode::SWAP, Opcode::RGt],
+                ),
+                ("NIP", vec![Opcode::SWAP, Opcode::DROP]),
+                (
+                    "TUCK",
+                    vec![
+                        Opcode::SWAP,
+                        Opcode::GtR,
+                        Opcode::DUP,
+                        Opcode::RGt,
+                        Opcode::SWAP,
+                    ],
+                ),
+                // Jump over the second DUP if the value is zero
+                (
+                    "?DUP",
+                    vec![Opcode::DUP, Opcode::LDI(2), Opcode::JRZ, Opcode::DUP],
+                ),
+                ("PICK", Syscall::Pick.opcodes()),
+                ("ROLL", Syscall::Roll.opcodes()),
+                ("DEPTH", Syscall::Depth.opcodes()),
+                (".", Syscall::Dot.opcodes()),
+                ("EMIT", Syscall::Emit.opcodes()),
+                (
+                    "CR",
+                    [vec![Opcode::LDI('\n' as i64)], Syscall::Emit.opcodes()].concat(),
+                ),
+                (
+                    "SPACE",
+                    [vec![Opcode::LDI(' ' as i64)], Syscall::Emit.opcodes()].concat(),
+                ),
                 ("1+", vec![Opcode::LDI(1), Opcode::ADD]),
                 ("1-", vec![Opcode::LDI(-1), Opcode::ADD]),
                 ("2+", vec![Opcode::LDI(2), Opcode::ADD]),
@@ -60,15 +168,225 @@ impl Default for ForthCompiler {
                 ("I", vec![Opcode::GETLP]),
                 ("J", vec![Opcode::GETLP2]),
                 ("AND", vec![Opcode::AND]),
+                ("EXECUTE", vec![Opcode::CALL]),
+                ("@", vec![Opcode::LDI(1), Opcode::MOVEFROMCELLS]),
+                ("!", vec![Opcode::LDI(1), Opcode::MOVETOCELLS]),
+                // Fetch, add, then store back to the address we kept a copy of
+                (
+                    "+!",
+                    vec![
+                        Opcode::DUP,
+                        Opcode::LDI(1),
+                        Opcode::MOVEFROMCELLS,
+                        Opcode::SWAP,
+                        Opcode::GtR,
+                        Opcode::ADD,
+                        Opcode::RGt,
+                        Opcode::LDI(1),
+                        Opcode::MOVETOCELLS,
+                    ],
+                ),
+                // SUB leaves the top of the stack minus the one below it, so -1 - x and 0 - x
+                ("INVERT", vec![Opcode::LDI(-1), Opcode::SUB]),
+                ("NEGATE", vec![Opcode::LDI(0), Opcode::SUB]),
+                // De Morgan, NOT (NOT a AND NOT b)
+                (
+                    "OR",
+                    vec![
+                        Opcode::LDI(-1),
+                        Opcode::SUB,
+                        Opcode::SWAP,
+                        Opcode::LDI(-1),
+                        Opcode::SUB,
+                        Opcode::AND,
+                        Opcode::LDI(-1),
+                        Opcode::SUB,
+                    ],
+                ),
+                // (a OR b) AND NOT (a AND b)
+                (
+                    "XOR",
+                    vec![
+                        Opcode::DUP2,
+                        Opcode::AND,
+                        Opcode::LDI(-1),
+                        Opcode::SUB,
+                        Opcode::GtR,
+                        Opcode::LDI(-1),
+                        Opcode::SUB,
+                        Opcode::SWAP,
+                        Opcode::LDI(-1),
+                        Opcode::SUB,
+                        Opcode::AND,
+                        Opcode::LDI(-1),
+                        Opcode::SUB,
+                        Opcode::RGt,
+                        Opcode::AND,
+                    ],
+                ),
+                ("LSHIFT", Syscall::LShift.opcodes()),
+                ("RSHIFT", Syscall::RShift.opcodes()),
+                // a - (a / b) * b
+                (
+                    "MOD",
+                    vec![
+                        Opcode::DUP2,
+                        Opcode::DIV,
+                        Opcode::MUL,
+                        Opcode::SWAP,
+                        Opcode::SUB,
+                    ],
+                ),
+                // Park the quotient on the scratch stack while working out the remainder
+                (
+                    "/MOD",
+                    vec![
+                        Opcode::DUP2,
+                        Opcode::DIV,
+                        Opcode::GtR,
+                        Opcode::DUP2,
+                        Opcode::DIV,
+                        Opcode::MUL,
+                        Opcode::SWAP,
+                        Opcode::SUB,
+                        Opcode::RGt,
+                    ],
+                ),
+                // DUP 0< IF NEGATE THEN
+                (
+                    "ABS",
+                    vec![
+                        Opcode::DUP,
+                        Opcode::LDI(0),
+                        Opcode::SWAP,
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPZ,
+                        Opcode::LDI(3),
+                        Opcode::JRZ,
+                        Opcode::LDI(0),
+                        Opcode::SUB,
+                    ],
+                ),
+                // 2DUP > IF SWAP THEN DROP
+                (
+                    "MIN",
+                    vec![
+                        Opcode::DUP2,
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPZ,
+                        Opcode::LDI(2),
+                        Opcode::JRZ,
+                        Opcode::SWAP,
+                        Opcode::DROP,
+                    ],
+                ),
+                // 2DUP < IF SWAP THEN DROP
+                (
+                    "MAX",
+                    vec![
+                        Opcode::DUP2,
+                        Opcode::SWAP,
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPZ,
+                        Opcode::LDI(2),
+                        Opcode::JRZ,
+                        Opcode::SWAP,
+                        Opcode::DROP,
+                    ],
+                ),
                 ("=", vec![Opcode::SUB, Opcode::CMPZ]),
                 ("<>", vec![Opcode::SUB, Opcode::CMPNZ]),
+                // The only ordered comparison the StackMachine has is CMPLOOP, so these briefly
+                // push the two values as a loop (index on top) to compare index >= limit
+                (
+                    "<",
+                    vec![
+                        Opcode::SWAP,
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPZ,
+                    ],
+                ),
+                (
+                    ">",
+                    vec![
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPZ,
+                    ],
+                ),
+                (
+                    "<=",
+                    vec![
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPNZ,
+                    ],
+                ),
+                (
+                    ">=",
+                    vec![
+                        Opcode::SWAP,
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPNZ,
+                    ],
+                ),
+                ("0=", vec![Opcode::CMPZ]),
+                (
+                    "0<",
+                    vec![
+                        Opcode::LDI(0),
+                        Opcode::SWAP,
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPZ,
+                    ],
+                ),
+                (
+                    "0>",
+                    vec![
+                        Opcode::LDI(0),
+                        Opcode::PUSHLP,
+                        Opcode::CMPLOOP,
+                        Opcode::DROPLP,
+                        Opcode::CMPZ,
+                    ],
+                ),
             ]),
             word_addresses: HashMap::new(),
-            last_function: 0,
+            last_function: FIRST_WORD_ADDRESS,
+            dictionary: Vec::new(),
             #[cfg(feature = "enable_reflection")]
             word_definitions: HashMap::new(),
             #[cfg(feature = "enable_reflection")]
             word_opcodes: HashMap::new(),
+            cells_reserved: 0,
+            cells_allocated: 0,
+            value_addresses: HashMap::new(),
+            deferred_slots: HashMap::new(),
+            output: Box::new(io::stdout()),
+            word_being_compiled: None,
+            pending_syscall,
+            interactive_source: Vec::new(),
+            last_backtrace: None,
+            last_error_location: None,
+            optimize: false,
+            check_stack_effects: false,
+            gas_used: 0,
+            backtraces: false,
+            inline_threshold: 0,
         }
     }
 }
@@ -78,13 +396,15 @@ impl Default for ForthCompiler {
 struct DeferredIfStatement {
     if_location: usize,
     else_location: Option<usize>,
+    token_position: usize,
 }
 
 impl DeferredIfStatement {
-    pub fn new(if_location: usize) -> Self {
+    pub fn new(if_location: usize, token_position: usize) -> Self {
         Self {
             if_location,
             else_location: None,
+            token_position,
         }
     }
 }
@@ -94,13 +414,31 @@ impl DeferredIfStatement {
 struct DeferredDoLoopStatement {
     _prelude_start: usize, // This is only used for debugging the internal code
     logical_start: usize,
+    // Where ?DO jumps from to skip the whole loop
+    skip_location: Option<usize>,
+    // UNLOOP has already dropped this loop, ready for an EXIT
+    unlooped: bool,
+    token_position: usize,
 }
 
 impl DeferredDoLoopStatement {
-    pub fn new(_prelude_start: usize, logical_start: usize) -> Self {
+    pub fn new(_prelude_start: usize, logical_start: usize, token_position: usize) -> Self {
         Self {
             _prelude_start,
             logical_start,
+            skip_location: None,
+            unlooped: false,
+            token_position,
+        }
+    }
+
+    // Called once the loop has been completely compiled
+    fn fixup_skip(&self, opcode_vector: &mut [Opcode]) {
+        if let Some(skip_location) = self.skip_location {
+            let jump_forward = i64::try_from(opcode_vector.len()).unwrap()
+                - i64::try_from(skip_location).unwrap()
+                - 1;
+            opcode_vector[skip_location] = Opcode::LDI(jump_forward);
         }
     }
 }
@@ -134,11 +472,87 @@ impl LoopExits {
 #[derive(Debug)]
 struct DeferredBeginLoopStatement {
     logical_start: usize,
+    token_position: usize,
 }
 
 impl DeferredBeginLoopStatement {
-    pub fn new(logical_start: usize) -> Self {
-        Self { logical_start }
+    pub fn new(logical_start: usize, token_position: usize) -> Self {
+        Self {
+            logical_start,
+            token_position,
+        }
+    }
+}
+
+// This struct tracks information for Forth CASE statements
+#[derive(Debug)]
+struct DeferredCaseStatement {
+    // The jump past the current OF ... ENDOF when the value doesn't match
+    of_location: Option<usize>,
+    token_position: usize,
+}
+
+impl DeferredCaseStatement {
+    pub fn new(token_position: usize) -> Self {
+        Self {
+            of_location: None,
+            token_position,
+        }
+    }
+}
+
+// This struct tracks a word while it is being compiled. Where it goes in memory isn't known until
+// it has been compiled, so calls to itself are patched afterwards
+#[derive(Debug)]
+struct WordBeingCompiled {
+    name: String,
+    recurse_locations: Vec<usize>,
+}
+
+impl WordBeingCompiled {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            recurse_locations: Vec::new(),
+        }
+    }
+
+    fn fixup_recursion(&self, opcode_vector: &mut [Opcode], function_start: usize) {
+        for recurse_point in self.recurse_locations.iter() {
+            opcode_vector[*recurse_point] = Opcode::LDI(function_start as i64);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WordKind {
+    Word,
+    // Running a MARKER word forgets it and everything defined after it
+    Marker,
+    // A FORWARD declared word that hasn't been defined yet, its callers get patched when it is
+    Forward,
+}
+
+// This struct tracks where a compiled word lives in memory
+#[derive(Debug)]
+struct DictionaryEntry {
+    name: String,
+    start: usize,
+    length: usize,
+    kind: WordKind,
+    // Once ' has handed out the address of a word, anything could call it, so it is never reclaimed
+    execution_token_taken: bool,
+    // Marked INLINE, so it is copied into its callers whatever its size
+    inline: bool,
+    // From its ( before -- after ) comment, or worked out from its code when it has none
+    stack_effect: Option<StackEffect>,
+    // The source token each opcode was compiled from, for backtraces
+    source_map: Vec<Option<String>>,
+}
+
+impl DictionaryEntry {
+    fn end(&self) -> usize {
+        self.start + self.length
     }
 }
 
@@ -146,29 +560,72 @@ enum DeferredStatement {
     If(DeferredIfStatement),
     DoLoop(DeferredDoLoopStatement, LoopExits),
     BeginLoop(DeferredBeginLoopStatement, LoopExits),
+    // Each ENDOF jumps out of the CASE, the same way LEAVE gets out of a loop
+    Case(DeferredCaseStatement, LoopExits),
+}
+
+impl DeferredStatement {
+    // The word that started this statement, and where it is in the source
+    fn opened_by(&self) -> (&'static str, usize) {
+        match self {
+            DeferredStatement::If(x) => ("IF", x.token_position),
+            DeferredStatement::DoLoop(x, _) if x.skip_location.is_some() => {
+                ("?DO", x.token_position)
+            }
+            DeferredStatement::DoLoop(x, _) => ("DO", x.token_position),
+            DeferredStatement::BeginLoop(x, _) => ("BEGIN", x.token_position),
+            DeferredStatement::Case(x, _) => ("CASE", x.token_position),
+        }
+    }
 }
 
 impl ForthCompiler {
+    #[cfg(test)]
     fn compile_tokens_compile_and_remove_word_definitions(
         &mut self,
         token_source: &ForthTokenizer,
     ) -> Result<Vec<Opcode>, ForthError> {
+        self.compile_tokens_from(token_source, 0)
+            .map(|(compiled_tokens, _)| compiled_tokens)
+    }
+
+    // Compile the tokens from position start on. Word definitions go into memory and the
+    // interactive code is returned. FORGET and marker words change the dictionary as they are
+    // compiled, and SEE prints as it is compiled, so if interactive code comes before one,
+    // compiling stops there so that code can run first, and the position to carry on from is
+    // returned with it
+    fn compile_tokens_from(
+        &mut self,
+        token_source: &ForthTokenizer,
+        start: usize,
+    ) -> Result<(Vec<Opcode>, Option<usize>), ForthError> {
         // This is the interactive compiled token list
         let mut tvi = Vec::new();
+        let mut resume_at = None;
 
         // Because we consume tokens in an inner loop, we can't use the normal for loop to read the tokens
-        let mut iter = token_source.into_iter();
-        while let Some(token) = iter.next() {
+        // Each token keeps its position in the source so errors can say where they happened
+        let mut iter = token_source.into_iter().enumerate().skip(start);
+        while let Some((position, token)) = iter.next() {
+            let acts_when_compiled = match token {
+                ForthToken::Command("FORGET" | "SEE") => true,
+                ForthToken::Command(word_name) => self.is_marker(word_name),
+                _ => false,
+            };
+            if acts_when_compiled && !tvi.is_empty() {
+                resume_at = Some(position);
+                break;
+            }
             match token {
                 // If a colon token, then compile the word definition
                 ForthToken::Colon => {
                     // Get the next token which has to be a command token, or its an error, this token will be the name to compile to
-                    if let Some(ForthToken::Command(word_name)) = iter.next() {
+                    if let Some((_, ForthToken::Command(word_name))) = iter.next() {
                         // This is the list of tokens we will be compiling
                         let mut tvc = Vec::new();
                         let mut found_semicolon = false;
                         // Because this is an inner loop using the outer iterator, we can't use the normal for loop syntax
-                        for token in iter.by_ref() {
+                        for (position, token) in iter.by_ref() {
                             match token {
                                 ForthToken::SemiColon => {
                                     // We have found the end of the word definition, so compile to opcodes and put into memory...
@@ -176,72 +633,569 @@ impl ForthCompiler {
                                     found_semicolon = true;
                                     break;
                                 }
-                                _ => tvc.push(token),
+                                _ => tvc.push((position, token)),
                             }
                         }
                         if !found_semicolon {
-                            return Err(ForthError::MissingSemicolonAfterColon);
+                            return Err(self.error_at(
+                                ForthError::MissingSemicolonAfterColon,
+                                position,
+                                Some(word_name.to_owned()),
+                            ));
                         }
                     } else {
                         // The command token has to be right after the colon token, we don't permit things like comments, we could though...
-                        return Err(ForthError::MissingCommandAfterColon);
+                        return Err(self.error_at(
+                            ForthError::MissingCommandAfterColon,
+                            position,
+                            None,
+                        ));
                     }
                 }
                 ForthToken::SemiColon => {
-                    return Err(ForthError::SemicolonBeforeColon);
+                    return Err(self.error_at(ForthError::SemicolonBeforeColon, position, None));
+                }
+                ForthToken::Command("FORGET") => {
+                    let word_name = self.name_following("FORGET", position, iter.next())?;
+                    self.forget_word(word_name)
+                        .map_err(|err| self.error_at(err, position, None))?;
+                }
+                ForthToken::Command("SEE") => {
+                    let word_name = self.name_following("SEE", position, iter.next())?;
+                    let listing = self
+                        .disassemble(word_name)
+                        .map_err(|err| self.error_at(err, position, None))?;
+                    self.output.write_all(listing.as_bytes())?;
+                }
+                // A marker is an empty word that remembers where the dictionary was
+                ForthToken::Command("MARKER") => {
+                    let word_name = self.name_following("MARKER", position, iter.next())?;
+                    self.compile_tokens_as_word(word_name, &[])?;
+                    if let Some(entry) = self.dictionary.last_mut() {
+                        entry.kind = WordKind::Marker;
+                    }
+                }
+                // : x ... ; INLINE copies x into everything that calls it from now on
+                ForthToken::Command("INLINE") => {
+                    let index = self
+                        .dictionary
+                        .len()
+                        .checked_sub(1)
+                        .filter(|&index| self.inline_body(index).is_some())
+                        .ok_or_else(|| {
+                            let err = ForthError::InvalidSyntax(
+                                "INLINE without a word that can be inlined before it".to_owned(),
+                            );
+                            self.error_at(err, position, None)
+                        })?;
+                    self.dictionary[index].inline = true;
+                }
+                // FORWARD x lets words call x before it is defined, the calls are pointed at the
+                // real x once it is. Until then running x is an error
+                ForthToken::Command("FORWARD") => {
+                    let word_name = self.name_following("FORWARD", position, iter.next())?;
+                    let mut compiled = undefined_word_code();
+                    let start = self.find_free_memory(compiled.len());
+                    compiled[0] = Opcode::LDI(start as i64);
+                    let source_map = vec![Some(format!("FORWARD {}", word_name)); compiled.len()];
+                    self.store_word(word_name, start, compiled, source_map, WordKind::Forward);
+                }
+                // DEFER x jumps to whatever word is in its cell, ' y IS x puts y there
+                ForthToken::Command("DEFER") => {
+                    let word_name = self.name_following("DEFER", position, iter.next())?;
+                    let slot = self.reserve_cell();
+                    let mut compiled = vec![
+                        Opcode::LDI(slot as i64),
+                        Opcode::LDI(1),
+                        Opcode::MOVEFROMCELLS,
+                        Opcode::JMP,
+                    ];
+                    // The cell starts out pointing at the error after the jump
+                    let unset_offset = compiled.len();
+                    compiled.append(&mut undefined_word_code());
+                    let start = self.find_free_memory(compiled.len());
+                    compiled[unset_offset] = Opcode::LDI(start as i64);
+                    let unset = start + unset_offset;
+                    let source_map = vec![Some(format!("DEFER {}", word_name)); compiled.len()];
+                    self.store_word(word_name, start, compiled, source_map, WordKind::Word);
+                    self.deferred_slots.insert(word_name.to_owned(), slot);
+                    tvi.push((position, ForthToken::Number(unset as i64)));
+                    tvi.push((position, ForthToken::Number(slot as i64)));
+                    tvi.push((position, ForthToken::Command("!")));
+                }
+                ForthToken::Command(word_name) if self.is_marker(word_name) => {
+                    self.forget_word(word_name)?;
+                }
+                // VARIABLE x leaves the address of its cell on the stack
+                ForthToken::Command("VARIABLE") => {
+                    let word_name = self.name_following("VARIABLE", position, iter.next())?;
+                    let address = self.reserve_cell();
+                    self.compile_tokens_as_word(
+                        word_name,
+                        &[(position, ForthToken::Number(address as i64))],
+                    )?;
+                }
+                // 10 CONSTANT x and 10 VALUE x both fetch from a cell that is set when the
+                // interactive code runs, so the value can come from anything left on the stack
+                ForthToken::Command(defining_word @ ("CONSTANT" | "VALUE")) => {
+                    let word_name = self.name_following(defining_word, position, iter.next())?;
+                    let address = self.reserve_cell();
+                    self.compile_tokens_as_word(
+                        word_name,
+                        &[
+                            (position, ForthToken::Number(address as i64)),
+                            (position, ForthToken::Command("@")),
+                        ],
+                    )?;
+                    if defining_word == "VALUE" {
+                        self.value_addresses.insert(word_name.to_owned(), address);
+                    }
+                    tvi.push((position, ForthToken::Number(address as i64)));
+                    tvi.push((position, ForthToken::Command("!")));
                 }
                 _ => {
-                    tvi.push(token);
+                    tvi.push((position, token));
                 }
             }
         }
 
-        let mut compiled_tokens = self.compile_token_vector(&tvi)?;
+        let mut source_map = Vec::new();
+        let mut compiled_tokens = self.compile_token_vector(&tvi, &mut source_map)?;
+
+        // Create any cells handed out since the last time, before anything can use them
+        if self.cells_reserved > self.cells_allocated {
+            let new_cells = i64::try_from(self.cells_reserved - self.cells_allocated)
+                .map_err(|_| ForthError::InternalNumericOverflow)?;
+            compiled_tokens.splice(0..0, [Opcode::LDI(new_cells), Opcode::NEWCELLS]);
+            source_map.splice(0..0, [None, None]);
+            self.cells_allocated = self.cells_reserved;
+        }
 
         // We need to return after running the interactive opcodes, so put the return in now
         compiled_tokens.push(Opcode::RET);
+        source_map.push(None);
+        self.interactive_source = source_map;
+
+        Ok((compiled_tokens, resume_at))
+    }
+
+    // The name following a word like VARIABLE or FORGET
+    fn name_following<'a>(
+        &mut self,
+        defining_word: &str,
+        position: usize,
+        token: Option<(usize, ForthToken<'a>)>,
+    ) -> Result<&'a str, ForthError> {
+        match token {
+            Some((_, ForthToken::Command(word_name))) => Ok(word_name),
+            _ => {
+                let err = ForthError::InvalidSyntax(format!(
+                    "{} without a name following it",
+                    defining_word
+                ));
+                Err(self.error_at(err, position, None))
+            }
+        }
+    }
+
+    // Remember where a compile error happened, unless something closer to it already has
+    fn error_at(&mut self, err: ForthError, token: usize, word: Option<String>) -> ForthError {
+        if self.last_error_location.is_none() {
+            self.last_error_location = Some(SourceLocation::new(token, word));
+        }
+        err
+    }
+
+    fn current_entry(&self, word_name: &str) -> Option<usize> {
+        let start = self.word_addresses.get(word_name)?;
+        self.dictionary
+            .iter()
+            .rposition(|entry| entry.start == *start && entry.name == word_name)
+    }
+
+    fn is_marker(&self, word_name: &str) -> bool {
+        self.current_entry(word_name)
+            .is_some_and(|index| self.dictionary[index].kind == WordKind::Marker)
+    }
 
-        Ok(compiled_tokens)
+    // The code a word can be replaced with at a call site, or None if it has to be called.
+    // Relative jumps inside it still land in the right place, as the body moves as a whole, and
+    // a jump to the RET at the end becomes a jump to whatever follows the call.
+    // Anything that leaves the word another way (EXIT, DEFER's JMP) needs a real call
+    fn inline_body(&self, index: usize) -> Option<Vec<Opcode>> {
+        let entry = &self.dictionary[index];
+        if entry.kind != WordKind::Word {
+            return None;
+        }
+        let mut body = match &self.sm.st.opcodes[entry.start..entry.end()] {
+            [body @ .., Opcode::RET] => body.to_vec(),
+            _ => return None,
+        };
+        // A tail call has to go back to being a call, now there is code after it
+        if let Some(last) = body.last_mut().filter(|op| **op == Opcode::JMP) {
+            *last = Opcode::CALL;
+        }
+        if body
+            .iter()
+            .any(|op| matches!(op, Opcode::RET | Opcode::JMP))
+        {
+            return None;
+        }
+        Some(body)
+    }
+
+    // The address ' gives out for a word
+    fn execution_token(&mut self, word_name: &str) -> Result<usize, ForthError> {
+        match self.current_entry(word_name) {
+            Some(index) => {
+                let entry = &mut self.dictionary[index];
+                entry.execution_token_taken = true;
+                Ok(entry.start)
+            }
+            None if self.intrinsic_words.contains_key(word_name) => Err(ForthError::InvalidSyntax(
+                format!("{} is built in, so it has no execution token", word_name),
+            )),
+            None => Err(ForthError::UnknownToken(word_name.to_owned())),
+        }
+    }
+
+    // Remove a word and everything defined after it from the dictionary, and give back their memory
+    fn forget_word(&mut self, word_name: &str) -> Result<(), ForthError> {
+        let index = self
+            .current_entry(word_name)
+            .ok_or_else(|| ForthError::UnknownToken(word_name.to_owned()))?;
+
+        // Everything before the last MARKER is always kept, so a definition from there that the
+        // word replaced comes back. A later one is gone, even if something still calls its code,
+        // so that what FORGET does doesn't depend on what else has been defined
+        let kept = self.dictionary[..=index]
+            .iter()
+            .rposition(|entry| entry.kind == WordKind::Marker)
+            .unwrap_or(0);
+        let forgotten = self.dictionary.split_off(index);
+        for entry in forgotten.iter() {
+            if let Some(earlier) = self.dictionary[..kept]
+                .iter()
+                .rev()
+                .find(|e| e.name == entry.name)
+            {
+                self.word_addresses
+                    .insert(entry.name.clone(), earlier.start);
+                #[cfg(feature = "enable_reflection")]
+                {
+                    self.word_opcodes.insert(
+                        entry.name.clone(),
+                        self.sm.st.opcodes[earlier.start..earlier.end()].to_vec(),
+                    );
+                    self.word_definitions.remove(&entry.name);
+                }
+            } else {
+                self.word_addresses.remove(&entry.name);
+                self.value_addresses.remove(&entry.name);
+                self.deferred_slots.remove(&entry.name);
+                #[cfg(feature = "enable_reflection")]
+                {
+                    self.word_opcodes.remove(&entry.name);
+                    self.word_definitions.remove(&entry.name);
+                }
+            }
+        }
+
+        // This also moves last_function back down to the end of the words that are left
+        self.reclaim_unreachable_words();
+        Ok(())
+    }
+
+    // Hand out the next StackMachine cell, it gets created before the interactive code runs
+    fn reserve_cell(&mut self) -> usize {
+        let address = self.cells_reserved;
+        self.cells_reserved += 1;
+        address
     }
 
     fn compile_tokens_as_word(
         &mut self,
         word_name: &str,
-        tokens: &[ForthToken],
+        tokens: &[(usize, ForthToken)],
     ) -> Result<(), ForthError> {
+        // Get the compiled assembler from the token vector
+        self.word_being_compiled = Some(WordBeingCompiled::new(word_name));
+        let mut source_map = Vec::new();
+        let compiled = self.compile_token_vector(tokens, &mut source_map);
+        let word_being_compiled = self.word_being_compiled.take();
+        let mut compiled = compiled?;
+        // Put the return OpCode onto the end
+        compiled.push(Opcode::RET);
+        source_map.push(Some(";".to_owned()));
+        let length = compiled.len();
+        // Reuse memory given back by reclaimed words if we can, otherwise go after the last function
+        let function_start = self.find_free_memory(length);
+        if let Some(word) = word_being_compiled {
+            word.fixup_recursion(&mut compiled, function_start);
+        }
+        // Tail calls leave the words that made them out of backtraces, so they come with -O
+        if self.optimize {
+            eliminate_tail_calls(&mut compiled);
+        }
+        let stack_effect = self.check_stack_effect(word_name, tokens, function_start, &compiled)?;
+        self.store_word(
+            word_name,
+            function_start,
+            compiled,
+            source_map,
+            WordKind::Word,
+        );
+        if let Some(index) = self.current_entry(word_name) {
+            self.dictionary[index].stack_effect = stack_effect;
+        }
+        #[cfg(feature = "enable_reflection")]
+        self.word_definitions.insert(
+            word_name.to_owned(),
+            format!(
+                "{:?}",
+                tokens.iter().map(|(_, token)| token).collect::<Vec<_>>()
+            ),
+        );
+        Ok(())
+    }
+
+    // With checking on, a definition starting with a ( before -- after ) comment has to do what
+    // it says. Returns the effect callers of the word can rely on, if there is one
+    fn check_stack_effect(
+        &mut self,
+        word_name: &str,
+        tokens: &[(usize, ForthToken)],
+        function_start: usize,
+        compiled: &[Opcode],
+    ) -> Result<Option<StackEffect>, ForthError> {
+        let declared = match tokens.first() {
+            Some((position, ForthToken::ParenthesizedRemark(remark)))
+                if self.check_stack_effects =>
+            {
+                StackEffect::parse(remark).map(|effect| (*position, effect))
+            }
+            _ => None,
+        };
+        // Recursive calls can only be followed if the comment says what they do
+        let analysis = stack_effect::analyze(compiled, |address| {
+            if address == function_start as i64 {
+                declared.map(|(_, effect)| effect)
+            } else {
+                self.dictionary
+                    .iter()
+                    .find(|entry| entry.start as i64 == address)
+                    .and_then(|entry| entry.stack_effect)
+            }
+        });
+
+        match (declared, analysis) {
+            (Some((position, declared)), analysis) => match analysis.mismatch(&declared) {
+                Some(actual) => {
+                    let err = ForthError::StackEffectMismatch {
+                        word: word_name.to_owned(),
+                        declared,
+                        actual,
+                    };
+                    Err(self.error_at(err, position, Some(word_name.to_owned())))
+                }
+                None => Ok(Some(declared)),
+            },
+            (None, Analysis::Effect(effect)) => Ok(Some(effect)),
+            (None, _) => Ok(None),
+        }
+    }
+
+    // Put compiled opcodes into memory at function_start (from find_free_memory) under word_name
+    fn store_word(
+        &mut self,
+        word_name: &str,
+        function_start: usize,
+        mut compiled: Vec<Opcode>,
+        source_map: Vec<Option<String>>,
+        kind: WordKind,
+    ) {
         // Remove anything extraneous from the end of the opcode array (*processor memory*),
         // typically previous immediate mode tokens
         self.sm.st.opcodes.resize(self.last_function, Opcode::NOP);
 
-        // Get the compiled assembler from the token vector
-        let mut compiled = self.compile_token_vector(tokens)?;
-        // Put the return OpCode onto the end
-        compiled.push(Opcode::RET);
-        // The current function start is the end of the last function
-        let function_start = self.last_function;
-        // Move last function pointer
-        self.last_function += compiled.len();
+        let length = compiled.len();
         // Remember the opcodes for reflection purposes if its enabled
         #[cfg(feature = "enable_reflection")]
         self.word_opcodes
             .insert(word_name.to_owned(), compiled.clone());
         // Add the function to the opcode memory
-        self.sm.st.opcodes.append(&mut compiled);
+        if function_start == self.last_function {
+            // Move last function pointer
+            self.last_function += length;
+            self.sm.st.opcodes.append(&mut compiled);
+        } else {
+            self.sm
+                .st
+                .opcodes
+                .splice(function_start..function_start + length, compiled);
+        }
+        let forward_start = self
+            .current_entry(word_name)
+            .filter(|&index| self.dictionary[index].kind == WordKind::Forward)
+            .map(|index| self.dictionary[index].start);
+        self.dictionary.push(DictionaryEntry {
+            name: word_name.to_owned(),
+            start: function_start,
+            length,
+            kind,
+            execution_token_taken: false,
+            inline: false,
+            stack_effect: None,
+            source_map,
+        });
+        // A new definition replaces whatever storage the name had before
+        self.value_addresses.remove(word_name);
+        self.deferred_slots.remove(word_name);
         // Remember where to find it...
-        self.word_addresses
-            .insert(word_name.to_owned(), function_start);
+        let redefined = self
+            .word_addresses
+            .insert(word_name.to_owned(), function_start)
+            .is_some();
+        if let Some(forward_start) = forward_start {
+            self.resolve_forward(forward_start, function_start);
+        }
+        // The old definition may not be needed any more
+        if redefined {
+            self.reclaim_unreachable_words();
+        }
         //        println!("Token Memory {:?}", self.sm.st.opcodes);
         //        println!("Word Addresses {:?}", self.word_addresses);
         //        println!("Last function {}", self.last_function);
-        #[cfg(feature = "enable_reflection")]
-        self.word_definitions
-            .insert(word_name.to_owned(), format!("{:?}", tokens));
-        Ok(())
     }
 
+    // Point every call to a FORWARD declared word at its real definition
+    fn resolve_forward(&mut self, forward_start: usize, function_start: usize) {
+        let opcodes = &mut self.sm.st.opcodes;
+        for entry in self.dictionary.iter() {
+            for address in entry.start..entry.end() - 1 {
+                if opcodes[address] == Opcode::LDI(forward_start as i64)
+                    && matches!(opcodes[address + 1], Opcode::CALL | Opcode::JMP)
+                {
+                    opcodes[address] = Opcode::LDI(function_start as i64);
+                }
+            }
+            #[cfg(feature = "enable_reflection")]
+            if self.word_addresses.get(&entry.name) == Some(&entry.start) {
+                self.word_opcodes.insert(
+                    entry.name.clone(),
+                    opcodes[entry.start..entry.end()].to_vec(),
+                );
+            }
+        }
+        // Anything holding the execution token of the FORWARD word gets sent on as well
+        opcodes[forward_start] = Opcode::LDI(function_start as i64);
+        opcodes[forward_start + 1] = Opcode::JMP;
+    }
+
+    // The first gap between compiled words big enough to hold length opcodes, or the end of memory
+    fn find_free_memory(&self, length: usize) -> usize {
+        let mut entries: Vec<&DictionaryEntry> = self.dictionary.iter().collect();
+        entries.sort_by_key(|entry| entry.start);
+
+        let mut free_start = FIRST_WORD_ADDRESS;
+        for entry in entries {
+            if entry.start - free_start >= length {
+                return free_start;
+            }
+            free_start = entry.end();
+        }
+        self.last_function
+    }
+
+    // Redefining a word leaves its old code behind, because words compiled against the old
+    // definition still call it. Once nothing reachable from a current word name calls old code
+    // any more, its memory is given back to be reused by later definitions.
+    // Everything defined before a MARKER is kept, as running the marker can bring it back.
+    fn reclaim_unreachable_words(&mut self) {
+        let entry_at: HashMap<usize, usize> = self
+            .dictionary
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.start, index))
+            .collect();
+
+        let mut reachable = vec![false; self.dictionary.len()];
+        let last_marker = self
+            .dictionary
+            .iter()
+            .rposition(|entry| entry.kind == WordKind::Marker)
+            .unwrap_or(0);
+        let mut to_visit: Vec<usize> = self
+            .dictionary
+            .iter()
+            .enumerate()
+            .filter(|(index, entry)| {
+                *index < last_marker
+                    || entry.execution_token_taken
+                    || self.word_addresses.get(&entry.name) == Some(&entry.start)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        while let Some(index) = to_visit.pop() {
+            if reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            let entry = &self.dictionary[index];
+            for pair in self.sm.st.opcodes[entry.start..entry.end()].windows(2) {
+                if let [Opcode::LDI(address), Opcode::CALL | Opcode::JMP] = pair {
+                    if let Some(&called) = usize::try_from(*address)
+                        .ok()
+                        .and_then(|address| entry_at.get(&address))
+                    {
+                        to_visit.push(called);
+                    }
+                }
+            }
+        }
+
+        let mut reachable = reachable.into_iter();
+        self.dictionary.retain(|_| reachable.next().unwrap_or(true));
+        self.last_function = self
+            .dictionary
+            .iter()
+            .map(DictionaryEntry::end)
+            .max()
+            .unwrap_or(FIRST_WORD_ADDRESS);
+        self.sm.st.opcodes.truncate(self.last_function);
+    }
+
+    // Errors say which token they happened at, and the word being compiled
+    // The source token behind each opcode is added to source_map
     fn compile_token_vector(
         &mut self,
-        token_vector: &[ForthToken],
+        token_vector: &[(usize, ForthToken)],
+        source_map: &mut Vec<Option<String>>,
+    ) -> Result<Vec<Opcode>, ForthError> {
+        let mut error_position = 0;
+        let mut tv = self
+            .compile_token_vector_at(token_vector, source_map, &mut error_position)
+            .map_err(|err| {
+                let word = self.word_being_compiled.as_ref().map(|w| w.name.clone());
+                self.error_at(err, error_position, word)
+            })?;
+        if self.optimize {
+            let moved = optimizer::optimize(&mut tv, source_map);
+            // The calls RECURSE left to fix up may have moved
+            if let Some(word) = self.word_being_compiled.as_mut() {
+                for location in word.recurse_locations.iter_mut() {
+                    *location = moved[*location];
+                }
+            }
+        }
+        Ok(tv)
+    }
+
+    fn compile_token_vector_at(
+        &mut self,
+        token_vector: &[(usize, ForthToken)],
+        source_map: &mut Vec<Option<String>>,
+        error_position: &mut usize,
     ) -> Result<Vec<Opcode>, ForthError> {
         // Stack of if statements, they are deferred until the THEN Forth word
         let mut deferred_statements = Vec::new();
@@ -249,11 +1203,22 @@ impl ForthCompiler {
         let mut tv: Vec<Opcode> = Vec::new();
 
         // Go through all the Forth tokens and turn them into processor Opcodes (for our StackMachine emulated processor)
-        for t in token_vector.iter() {
+        // TO takes the token after it, so we can't use the normal for loop
+        let mut tokens = token_vector.iter();
+        while let Some((position, t)) = tokens.next() {
+            *error_position = *position;
             match t {
                 ForthToken::DropLineComment(_) => (),
                 ForthToken::ParenthesizedRemark(_) => (),
-                ForthToken::StringCommand(_, _) => (),
+                // The bytes of the string go on the stack, followed by how many there are
+                ForthToken::StringCommand(".\"", text) => {
+                    tv.extend(text.bytes().map(|b| Opcode::LDI(i64::from(b))));
+                    tv.push(Opcode::LDI(text.len() as i64));
+                    tv.extend(Syscall::PrintString.opcodes());
+                }
+                ForthToken::StringCommand(s, _) => {
+                    return Err(ForthError::UnknownToken((*s).to_string()));
+                }
                 ForthToken::Number(n) => {
                     // Numbers get pushed as a LDI opcode
                     tv.push(Opcode::LDI(*n));
@@ -263,18 +1228,35 @@ impl ForthCompiler {
                     let current_instruction = tv.len();
 
                     match *s {
-                        "DO" => {
+                        "DO" | "?DO" => {
                             let start_of_loop_code = current_instruction;
+                            // ?DO skips the loop when the index already equals the limit
+                            let skip_location = if *s == "?DO" {
+                                tv.push(Opcode::DUP2);
+                                tv.push(Opcode::SUB);
+                                tv.push(Opcode::LDI(5));
+                                tv.push(Opcode::JRNZ);
+                                tv.push(Opcode::DROP);
+                                tv.push(Opcode::DROP);
+                                // We fix up the jump once we get the end of loop
+                                let skip_location = tv.len();
+                                tv.push(Opcode::LDI(0));
+                                tv.push(Opcode::JR);
+                                Some(skip_location)
+                            } else {
+                                None
+                            };
                             // This eats the loop parameters from the number stack...
                             tv.push(Opcode::PUSHLP);
                             let logical_start_of_loop = tv.len();
-                            deferred_statements.push(DeferredStatement::DoLoop(
-                                DeferredDoLoopStatement::new(
-                                    start_of_loop_code,
-                                    logical_start_of_loop,
-                                ),
-                                LoopExits::new(),
-                            ));
+                            let mut loop_def = DeferredDoLoopStatement::new(
+                                start_of_loop_code,
+                                logical_start_of_loop,
+                                *position,
+                            );
+                            loop_def.skip_location = skip_location;
+                            deferred_statements
+                                .push(DeferredStatement::DoLoop(loop_def, LoopExits::new()));
                         }
                         "LOOP" => {
                             if let Some(DeferredStatement::DoLoop(loop_def, loop_exits)) =
@@ -290,12 +1272,13 @@ impl ForthCompiler {
                                 tv.push(Opcode::JRZ);
 
                                 loop_exits.fixup_loop_exits(&mut tv);
+                                tv.push(Opcode::DROPLP);
+                                loop_def.fixup_skip(&mut tv);
                             } else {
                                 return Err(ForthError::InvalidSyntax(
                                     "LOOP without proper loop start like DO".to_owned(),
                                 ));
                             }
-                            tv.push(Opcode::DROPLP);
                         }
                         "+LOOP" => {
                             if let Some(DeferredStatement::DoLoop(loop_def, loop_exits)) =
@@ -311,12 +1294,13 @@ impl ForthCompiler {
                                 tv.push(Opcode::JRZ);
 
                                 loop_exits.fixup_loop_exits(&mut tv);
+                                tv.push(Opcode::DROPLP);
+                                loop_def.fixup_skip(&mut tv);
                             } else {
                                 return Err(ForthError::InvalidSyntax(
                                     "+LOOP without proper loop start like DO".to_owned(),
                                 ));
                             }
-                            tv.push(Opcode::DROPLP);
                         }
                         "LEAVE" => {
                             let most_recent_loop_statement =
@@ -324,6 +1308,7 @@ impl ForthCompiler {
                                     DeferredStatement::If(_) => false,
                                     DeferredStatement::DoLoop(_, _) => true,
                                     DeferredStatement::BeginLoop(_, _) => true,
+                                    DeferredStatement::Case(_, _) => false,
                                 });
                             if let Some(deferred_statement) = most_recent_loop_statement {
                                 let loop_exits =
@@ -350,7 +1335,7 @@ impl ForthCompiler {
                         }
                         "BEGIN" => {
                             deferred_statements.push(DeferredStatement::BeginLoop(
-                                DeferredBeginLoopStatement::new(current_instruction),
+                                DeferredBeginLoopStatement::new(current_instruction, *position),
                                 LoopExits::new(),
                             ));
                         }
@@ -422,10 +1407,129 @@ impl ForthCompiler {
                                 ));
                             }
                         }
+                        "TO" => {
+                            let address = match tokens.next() {
+                                Some((_, ForthToken::Command(value_name))) => {
+                                    self.value_addresses.get(*value_name)
+                                }
+                                _ => None,
+                            };
+                            if let Some(&address) = address {
+                                tv.push(Opcode::LDI(address as i64));
+                                tv.push(Opcode::LDI(1));
+                                tv.push(Opcode::MOVETOCELLS);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "TO without a VALUE name following it".to_owned(),
+                                ));
+                            }
+                        }
+                        "IS" => {
+                            let address = match tokens.next() {
+                                Some((_, ForthToken::Command(deferred_name))) => {
+                                    self.deferred_slots.get(*deferred_name)
+                                }
+                                _ => None,
+                            };
+                            if let Some(&address) = address {
+                                tv.push(Opcode::LDI(address as i64));
+                                tv.push(Opcode::LDI(1));
+                                tv.push(Opcode::MOVETOCELLS);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "IS without a DEFER name following it".to_owned(),
+                                ));
+                            }
+                        }
+                        // Everything is compiled ahead of time, so ' and ['] are the same thing
+                        "'" | "[']" => {
+                            if let Some((_, ForthToken::Command(word_name))) = tokens.next() {
+                                let address = self.execution_token(word_name)?;
+                                tv.push(Opcode::LDI(address as i64));
+                            } else {
+                                return Err(ForthError::InvalidSyntax(format!(
+                                    "{} without a word name following it",
+                                    s
+                                )));
+                            }
+                        }
+                        "CASE" => {
+                            deferred_statements.push(DeferredStatement::Case(
+                                DeferredCaseStatement::new(*position),
+                                LoopExits::new(),
+                            ));
+                        }
+                        // OVER = IF DROP, the value being tested stays on the stack until a match
+                        "OF" => {
+                            if let Some(DeferredStatement::Case(case_def, _)) =
+                                deferred_statements.last_mut()
+                            {
+                                if case_def.of_location.is_some() {
+                                    return Err(ForthError::InvalidSyntax(
+                                        "OF without ENDOF before it".to_owned(),
+                                    ));
+                                }
+                                tv.extend_from_slice(&self.intrinsic_words["OVER"]);
+                                tv.push(Opcode::SUB);
+                                tv.push(Opcode::CMPZ);
+                                case_def.of_location = Some(tv.len());
+                                // We fix up the jump once we get to the ENDOF
+                                tv.push(Opcode::LDI(0));
+                                tv.push(Opcode::JRZ);
+                                tv.push(Opcode::DROP);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "OF without CASE".to_owned(),
+                                ));
+                            }
+                        }
+                        "ENDOF" => {
+                            if let Some(DeferredStatement::Case(case_def, case_exits)) =
+                                deferred_statements.last_mut()
+                            {
+                                if let Some(of_location) = case_def.of_location.take() {
+                                    // We fix up the jumps once we get the ENDCASE
+                                    case_exits.add_exit_point(current_instruction);
+                                    tv.push(Opcode::LDI(0));
+                                    tv.push(Opcode::JR);
+                                    // No match carries on after this ENDOF
+                                    let jump_forward = i64::try_from(tv.len()).unwrap()
+                                        - i64::try_from(of_location).unwrap()
+                                        - 1;
+                                    tv[of_location] = Opcode::LDI(jump_forward);
+                                } else {
+                                    return Err(ForthError::InvalidSyntax(
+                                        "ENDOF without OF".to_owned(),
+                                    ));
+                                }
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "ENDOF without CASE".to_owned(),
+                                ));
+                            }
+                        }
+                        "ENDCASE" => {
+                            if let Some(DeferredStatement::Case(case_def, case_exits)) =
+                                deferred_statements.pop()
+                            {
+                                if case_def.of_location.is_some() {
+                                    return Err(ForthError::InvalidSyntax(
+                                        "ENDCASE without ENDOF".to_owned(),
+                                    ));
+                                }
+                                // Nothing matched, so the value is still there
+                                tv.push(Opcode::DROP);
+                                case_exits.fixup_loop_exits(&mut tv);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "ENDCASE without CASE".to_owned(),
+                                ));
+                            }
+                        }
                         // FLAG 0 = Skip stuff inside IF, !0 = Run stuff inside IF
                         "IF" => {
                             deferred_statements.push(DeferredStatement::If(
-                                DeferredIfStatement::new(current_instruction),
+                                DeferredIfStatement::new(current_instruction, *position),
                             ));
                             //println!("(IF)Deferred If Stack {:?}", deferred_if_statements);
                             tv.push(Opcode::LDI(0));
@@ -454,9 +1558,10 @@ impl ForthCompiler {
                                         - (x.if_location + 1) as u64)
                                         .try_into()
                                         .unwrap(),
-                                    Some(el) => (current_instruction as u64 - el as u64 + 1)
-                                        .try_into()
-                                        .unwrap(),
+                                    // Jump to just past the ELSE jump
+                                    Some(el) => {
+                                        (el as u64 - x.if_location as u64 + 1).try_into().unwrap()
+                                    }
                                 };
                                 let (else_jump_location, else_jump_offset): (
                                     Option<usize>,
@@ -486,12 +1591,77 @@ impl ForthCompiler {
                                 ));
                             }
                         }
+                        // Return early, dropping the loop stack entries of any DO loops we are in,
+                        // apart from those an UNLOOP has already dropped
+                        "EXIT" => {
+                            for statement in deferred_statements.iter_mut() {
+                                if let DeferredStatement::DoLoop(loop_def, _) = statement {
+                                    if !loop_def.unlooped {
+                                        tv.push(Opcode::DROPLP);
+                                    }
+                                    // Code after the EXIT still runs inside the loop
+                                    loop_def.unlooped = false;
+                                }
+                            }
+                            tv.push(Opcode::RET);
+                        }
+                        "UNLOOP" => {
+                            let innermost_loop =
+                                deferred_statements.iter_mut().rev().find_map(|x| match x {
+                                    DeferredStatement::DoLoop(loop_def, _)
+                                        if !loop_def.unlooped =>
+                                    {
+                                        Some(loop_def)
+                                    }
+                                    _ => None,
+                                });
+                            if let Some(loop_def) = innermost_loop {
+                                loop_def.unlooped = true;
+                                tv.push(Opcode::DROPLP);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "UNLOOP without proper loop start like DO".to_owned(),
+                                ));
+                            }
+                        }
+                        "RECURSE" => {
+                            if let Some(word) = self.word_being_compiled.as_mut() {
+                                // We fix up the call once we know where the word goes
+                                word.recurse_locations.push(current_instruction);
+                                tv.push(Opcode::LDI(0));
+                                tv.push(Opcode::CALL);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "RECURSE outside of a word definition".to_owned(),
+                                ));
+                            }
+                        }
                         _ => {
-                            if let Some(&offset) = self.word_addresses.get(*s) {
+                            let inline_body = self
+                                .current_entry(s)
+                                .filter(|&index| {
+                                    let entry = &self.dictionary[index];
+                                    entry.inline
+                                        || (self.inline_threshold > 0
+                                            && entry.length <= self.inline_threshold + 1)
+                                })
+                                .and_then(|index| self.inline_body(index));
+                            if let Some(body) = inline_body {
+                                tv.extend(body);
+                            } else if let Some(&offset) = self.word_addresses.get(*s) {
                                 tv.push(Opcode::LDI(offset as i64));
                                 tv.push(Opcode::CALL);
                             } else if let Some(ol) = self.intrinsic_words.get(*s) {
                                 tv.extend_from_slice(ol);
+                            } else if let Some(word) = self
+                                .word_being_compiled
+                                .as_mut()
+                                .filter(|word| word.name == *s)
+                            {
+                                // A word calling itself by name before it has an earlier definition
+                                word.recurse_locations.push(current_instruction);
+                                tv.push(Opcode::LDI(0));
+                                tv.push(Opcode::CALL);
                             } else {
                                 return Err(ForthError::UnknownToken((*s).to_string()));
                             }
@@ -505,6 +1675,17 @@ impl ForthCompiler {
                     unreachable!("SemiColon should never reach this function");
                 }
             }
+            source_map.resize(tv.len(), Some(source::token_text(t)));
+        }
+
+        // Anything still open would leave a jump nowhere, or the loop stack unbalanced
+        if let Some(statement) = deferred_statements.pop() {
+            let (word, position) = statement.opened_by();
+            *error_position = position;
+            return Err(ForthError::UnterminatedControlStructure {
+                word: word.to_owned(),
+                position,
+            });
         }
 
         Ok(tv)
@@ -515,19 +1696,408 @@ impl ForthCompiler {
         token_source: &ForthTokenizer,
         gas_limit: GasLimit,
     ) -> Result<(), ForthError> {
-        let mut ol = self.compile_tokens_compile_and_remove_word_definitions(token_source)?;
-        //println!("Compiled Opcodes: {:?}", ol);
-        self.sm.st.opcodes.resize(self.last_function, Opcode::NOP);
-        self.sm.st.opcodes.append(&mut ol);
-        self.sm.execute(self.last_function, gas_limit)?;
-        //println!("Total opcodes defined: {}", self.sm.st.opcodes.len());
-        //println!("Total opcodes executed: {}", self.sm.st.gas_used());
+        // The gas limit covers every piece of interactive code the tokens are split into
+        let mut gas_used = 0;
+        let mut start = 0;
+        loop {
+            let (mut ol, resume_at) = self.compile_tokens_from(token_source, start)?;
+            //println!("Compiled Opcodes: {:?}", ol);
+            self.sm.st.opcodes.resize(self.last_function, Opcode::NOP);
+            self.sm.st.opcodes.append(&mut ol);
+            let remaining_gas = match gas_limit {
+                GasLimit::Unlimited => GasLimit::Unlimited,
+                GasLimit::Limited(limit) => GasLimit::Limited(limit.saturating_sub(gas_used)),
+            };
+            let result = self.run(self.last_function, remaining_gas);
+            gas_used += self.gas_used;
+            self.gas_used = gas_used;
+            match result {
+                Err(ForthError::RanOutOfGas { .. }) => {
+                    return Err(ForthError::RanOutOfGas {
+                        gas_used,
+                        gas_limit,
+                    });
+                }
+                r => r?,
+            }
+            //println!("Total opcodes defined: {}", self.sm.st.opcodes.len());
+            //println!("Total opcodes executed: {}", self.sm.st.gas_used());
+
+            match resume_at {
+                Some(position) => start = position,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Run the StackMachine, servicing any syscalls the compiled code makes along the way.
+    // The gas limit covers the whole run, not each restart of the StackMachine.
+    // If it fails, the words that were running are left in last_backtrace
+    fn run(&mut self, starting_point: usize, gas_limit: GasLimit) -> Result<(), ForthError> {
+        self.last_backtrace = None;
+        let result = if self.backtraces {
+            // The StackMachine doesn't let us see where it is, so it runs one opcode at a time
+            // while we follow along with our own copy of its program counter and return stack
+            let mut pc = starting_point;
+            let mut return_stack = Vec::new();
+            let result = self.step_until_done(&mut pc, &mut return_stack, gas_limit);
+            if result.is_err() {
+                self.last_backtrace = Some(self.backtrace(pc, &return_stack));
+            }
+            result
+        } else {
+            self.execute_until_done(starting_point, gas_limit)
+        };
+        if result.is_err() {
+            self.clear_control_stacks();
+        }
+        self.output.flush()?;
+        result
+    }
+
+    fn execute_until_done(
+        &mut self,
+        starting_point: usize,
+        gas_limit: GasLimit,
+    ) -> Result<(), ForthError> {
+        self.gas_used = 0;
+        let mut pc = starting_point;
+        loop {
+            let remaining_gas = match gas_limit {
+                GasLimit::Unlimited => GasLimit::Unlimited,
+                GasLimit::Limited(limit) => GasLimit::Limited(limit.saturating_sub(self.gas_used)),
+            };
+            let result = self.sm.execute(pc, remaining_gas);
+            self.gas_used += self.sm.st.gas_used();
+            match result {
+                Err(StackMachineError::RanOutOfGas { .. }) => {
+                    return Err(ForthError::RanOutOfGas {
+                        gas_used: self.gas_used,
+                        gas_limit,
+                    });
+                }
+                r => r?,
+            }
+
+            match self.pending_syscall.take() {
+                Some(syscall) => {
+                    self.execute_syscall(syscall)?;
+                    pc = SYSCALL_RESUME;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn step_until_done(
+        &mut self,
+        pc: &mut usize,
+        return_stack: &mut Vec<usize>,
+        gas_limit: GasLimit,
+    ) -> Result<(), ForthError> {
+        self.gas_used = 0;
+        loop {
+            // Work out where the opcode goes next, before it changes the stacks
+            let top = self.sm.st.number_stack.last().copied();
+            let next = match self.sm.st.opcodes.get(*pc) {
+                Some(Opcode::CALL | Opcode::JMP) => top.and_then(|t| usize::try_from(t).ok()),
+                Some(Opcode::JR) => top.map(|offset| (*pc as i64 + offset) as usize),
+                Some(opcode @ (Opcode::JRZ | Opcode::JRNZ)) => {
+                    let stack = &self.sm.st.number_stack;
+                    let value = stack.len().checked_sub(2).map(|index| stack[index]);
+                    match (top, value) {
+                        (Some(offset), Some(value)) if (value == 0) == (*opcode == Opcode::JRZ) => {
+                            Some((*pc as i64 + offset) as usize)
+                        }
+                        _ => Some(*pc + 1),
+                    }
+                }
+                Some(Opcode::RET) => return_stack.last().copied(),
+                _ => Some(*pc + 1),
+            };
+
+            match self.sm.execute(*pc, GasLimit::Limited(0)) {
+                // Running out of gas straight after the first opcode means it ran fine
+                Err(StackMachineError::RanOutOfGas { .. }) => {
+                    match self.sm.st.opcodes[*pc] {
+                        Opcode::CALL => return_stack.push(*pc + 1),
+                        Opcode::RET => {
+                            return_stack.pop();
+                        }
+                        _ => (),
+                    }
+                    *pc = next.unwrap_or_default();
+                    self.gas_used += 1;
+                    if let GasLimit::Limited(limit) = gas_limit {
+                        if self.gas_used > limit {
+                            return Err(ForthError::RanOutOfGas {
+                                gas_used: self.gas_used,
+                                gas_limit,
+                            });
+                        }
+                    }
+                }
+                // A handled TRAP, or the final RET
+                Ok(()) => match self.pending_syscall.take() {
+                    Some(syscall) => {
+                        self.execute_syscall(syscall)?;
+                        *pc = SYSCALL_RESUME;
+                    }
+                    None => return Ok(()),
+                },
+                // checked_div fails for MIN / -1 as well, which doesn't fit rather than divides by 0
+                Err(StackMachineError::DivisionByZero { failing_opcode }) if top != Some(0) => {
+                    return Err(ForthError::NumericOverflow {
+                        opcode: failing_opcode,
+                        address: Some(*pc),
+                    });
+                }
+                Err(err) => return Err(ForthError::from(err).at_address(*pc)),
+            }
+        }
+    }
 
+    // The words that were running, from the failing opcode at pc out through the calls to it
+    fn backtrace(&self, pc: usize, return_stack: &[usize]) -> Backtrace {
+        let calls = return_stack.iter().rev().map(|address| address - 1);
+        let frames = std::iter::once(pc)
+            .chain(calls)
+            // The syscall entry point isn't interesting, the call to it is
+            .filter(|&address| address >= FIRST_WORD_ADDRESS)
+            .map(|address| {
+                let entry = self
+                    .dictionary
+                    .iter()
+                    .find(|entry| entry.start <= address && address < entry.end());
+                let (word, token) = match entry {
+                    Some(entry) => (
+                        Some(entry.name.clone()),
+                        entry.source_map.get(address - entry.start),
+                    ),
+                    // Either interactive code, or memory no word owns any more
+                    None => (
+                        None,
+                        address
+                            .checked_sub(self.last_function)
+                            .and_then(|offset| self.interactive_source.get(offset)),
+                    ),
+                };
+                BacktraceFrame {
+                    word,
+                    address,
+                    token: token.cloned().flatten(),
+                }
+            })
+            .collect();
+        Backtrace { frames }
+    }
+
+    // After an error the StackMachine can be left part way through calls and loops, which would
+    // confuse the next run, so empty its return, loop and scratch stacks
+    fn clear_control_stacks(&mut self) {
+        // A RET sits just after the syscall TRAP, with an empty return stack it stops
+        while let Err(StackMachineError::RanOutOfGas { .. }) =
+            self.sm.execute(SYSCALL_RESUME, GasLimit::Limited(0))
+        {}
+        // There is nothing after the interactive code, so borrow the space for a DROPLP
+        let droplp = self.sm.st.opcodes.len();
+        self.sm.st.opcodes.push(Opcode::DROPLP);
+        while let Err(StackMachineError::RanOutOfGas { .. }) =
+            self.sm.execute(droplp, GasLimit::Limited(0))
+        {}
+        self.sm.st.opcodes.truncate(droplp);
+        self.sm.st.scratch_stack.clear();
+    }
+
+    /// Run compiled code one opcode at a time, so that runtime errors know the address that
+    /// failed and last_backtrace can say which words were running, it starts off. This is several
+    /// times slower. Without it the StackMachine can't tell MIN / -1 from dividing by zero, so
+    /// that is reported as DivisionByZero rather than NumericOverflow
+    pub fn set_backtraces(&mut self, backtraces: bool) {
+        self.backtraces = backtraces;
+    }
+
+    /// The Forth words that were running when the last run failed at runtime, innermost first.
+    /// Only recorded with set_backtraces on
+    pub fn last_backtrace(&self) -> Option<&Backtrace> {
+        self.last_backtrace.as_ref()
+    }
+
+    fn execute_syscall(&mut self, syscall: Syscall) -> Result<(), ForthError> {
+        let number_stack = &mut self.sm.st.number_stack;
+        match syscall {
+            Syscall::Depth => {
+                let depth = i64::try_from(number_stack.len())
+                    .map_err(|_| ForthError::InternalNumericOverflow)?;
+                number_stack.push(depth);
+            }
+            Syscall::Pick => {
+                let index = stack_index_from_top(number_stack)?;
+                number_stack.push(number_stack[index]);
+            }
+            Syscall::Roll => {
+                let index = stack_index_from_top(number_stack)?;
+                let value = number_stack.remove(index);
+                number_stack.push(value);
+            }
+            // Shifting by 64 bits or more shifts everything out
+            Syscall::LShift => {
+                let (value, shift) = pop_shift_operands(number_stack)?;
+                number_stack.push(value.checked_shl(shift).unwrap_or(0));
+            }
+            // RSHIFT is a logical shift, zeroes come in at the top
+            Syscall::RShift => {
+                let (value, shift) = pop_shift_operands(number_stack)?;
+                number_stack.push((value as u64).checked_shr(shift).unwrap_or(0) as i64);
+            }
+            Syscall::Dot => {
+                let value = number_stack.pop().ok_or(ForthError::NumberStackUnderflow)?;
+                write!(self.output, "{} ", value)?;
+            }
+            Syscall::Emit => {
+                let value = number_stack.pop().ok_or(ForthError::NumberStackUnderflow)?;
+                let c = u32::try_from(value)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                write!(self.output, "{}", c)?;
+            }
+            Syscall::UndefinedWord => {
+                let start = number_stack.pop().ok_or(ForthError::NumberStackUnderflow)?;
+                let word_name = self
+                    .dictionary
+                    .iter()
+                    .rfind(|entry| entry.start as i64 == start)
+                    .map_or_else(|| start.to_string(), |entry| entry.name.clone());
+                return Err(ForthError::UndefinedWord(word_name));
+            }
+            Syscall::PrintString => {
+                let length = number_stack.pop().ok_or(ForthError::NumberStackUnderflow)?;
+                let start = usize::try_from(length)
+                    .ok()
+                    .and_then(|length| number_stack.len().checked_sub(length))
+                    .ok_or(ForthError::NumberStackUnderflow)?;
+                let bytes: Vec<u8> = number_stack.drain(start..).map(|b| b as u8).collect();
+                self.output.write_all(&bytes)?;
+            }
+        }
         Ok(())
     }
 
+    /// Turn the peephole optimizer on or off for code compiled from now on, it starts off.
+    /// It folds constants and drops jumps that go nowhere, so the code uses less gas, and turns
+    /// calls at the end of words into jumps, so tail recursion doesn't grow the return stack
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Copy words this many opcodes long or shorter into the words and code that call them,
+    /// instead of calling them. It starts at 0, so only words marked INLINE are copied
+    pub fn set_inline_threshold(&mut self, opcodes: usize) {
+        self.inline_threshold = opcodes;
+    }
+
+    /// Turn checking definitions against their ( before -- after ) comments on or off for words
+    /// compiled from now on, it starts off. A definition whose code doesn't do what its comment
+    /// says fails to compile with StackEffectMismatch
+    pub fn set_check_stack_effects(&mut self, check: bool) {
+        self.check_stack_effects = check;
+    }
+
+    /// What a word does to the number stack, from its ( before -- after ) comment, or worked out
+    /// from its code. None if there is no knowing, for example if it uses EXECUTE
+    pub fn stack_effect(&self, word_name: &str) -> Option<StackEffect> {
+        let index = self.current_entry(word_name)?;
+        self.dictionary[index].stack_effect
+    }
+
+    /// How many opcodes were run the last time compiled code was
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    /// Send everything the Forth output words print to output, instead of stdout
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Where in the source the last compile error happened, if the last call to execute_string
+    /// failed to compile. It is kept here rather than in the ForthError, so the error variants
+    /// don't change shape
+    pub fn last_error_location(&self) -> Option<&SourceLocation> {
+        self.last_error_location.as_ref()
+    }
+
     pub fn execute_string(&mut self, s: &str, gas_limit: GasLimit) -> Result<(), ForthError> {
-        let tokenizer = ForthTokenizer::new(s);
-        self.execute_tokens(&tokenizer, gas_limit)
+        self.last_error_location = None;
+        let source = pad_source(s);
+        let tokenizer = ForthTokenizer::new(&source);
+        let result = self.execute_tokens(&tokenizer, gas_limit);
+        if let Some(location) = self.last_error_location.as_mut() {
+            location.resolve(&source);
+        }
+        result
+    }
+}
+
+// A call straight before a RET becomes a jump, so the called word returns straight to our caller
+// and the return stack doesn't grow, however deep tail recursion goes. Calls to the syscall entry
+// point are left alone, they don't use the return stack
+fn eliminate_tail_calls(compiled: &mut [Opcode]) {
+    for i in 0..compiled.len().saturating_sub(1) {
+        let syscall = i > 0 && compiled[i - 1] == Opcode::LDI(SYSCALL_ENTRY as i64);
+        if compiled[i] == Opcode::CALL && compiled[i + 1] == Opcode::RET && !syscall {
+            compiled[i] = Opcode::JMP;
+        }
+    }
+}
+
+// The code for a word that is an error to run, until it is given a definition. Its first opcode
+// pushes the address of the word for the error to name it by, which is filled in once the word
+// has somewhere to go
+fn undefined_word_code() -> Vec<Opcode> {
+    [
+        vec![Opcode::LDI(0)],
+        Syscall::UndefinedWord.opcodes(),
+        vec![Opcode::RET],
+    ]
+    .concat()
+}
+
+// Pops a PICK/ROLL style count off the stack, and turns it into an index into the stack
+fn stack_index_from_top(number_stack: &mut Vec<i64>) -> Result<usize, ForthError> {
+    let count = number_stack.pop().ok_or(ForthError::NumberStackUnderflow)?;
+    usize::try_from(count)
+        .ok()
+        .and_then(|count| number_stack.len().checked_sub(count + 1))
+        .ok_or(ForthError::NumberStackUnderflow)
+}
+
+// Pops the ( x u ) operands of LSHIFT/RSHIFT
+fn pop_shift_operands(number_stack: &mut Vec<i64>) -> Result<(i64, u32), ForthError> {
+    let shift = number_stack.pop().ok_or(ForthError::NumberStackUnderflow)?;
+    let value = number_stack.pop().ok_or(ForthError::NumberStackUnderflow)?;
+    // Anything that doesn't fit is far too big a shift anyway
+    Ok((value, u32::try_from(shift).unwrap_or(u32::MAX)))
+}
+
+// The tokenizer panics if the source ends in exactly one whitespace character, or right after
+// the closing delimiter of a string or parenthesized remark, so always end it with two spaces
+fn pad_source(s: &str) -> String {
+    format!("{}  ", s.trim_end())
+}
+
+/// True if the source has a : that hasn't been closed by a matching ; yet, so a REPL knows to
+/// keep reading lines before handing them to ForthCompiler::execute_string
+pub fn definition_is_open(source: &str) -> bool {
+    let source = pad_source(source);
+    let mut open = false;
+    for token in &ForthTokenizer::new(&source) {
+        match token {
+            ForthToken::Colon => open = true,
+            ForthToken::SemiColon => open = false,
+            _ => (),
+        }
     }
+    open
 }
@@ -1,159 +1,193 @@
-use rust_forth_compiler::{ForthCompiler, ForthError, GasLimit};
+use rust_forth_compiler::{ForthCompiler, ForthError, GasLimit, definition_is_open};
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
 use std::fs;
+use std::process;
 
-fn main() -> Result<(), ForthError> {
-    println!("Hello, world! This is rust-forth-compiler");
+// Generous enough for real work, but a runaway loop still stops instead of hanging
+const DEFAULT_GAS_LIMIT: u64 = 10_000_000;
 
-    run()?;
+// With --optimize, words up to this many opcodes long are inlined
+const OPTIMIZE_INLINE_THRESHOLD: usize = 8;
 
-    Ok(())
-}
-
-fn run() -> Result<(), ForthError> {
-    let mut fc = ForthCompiler::default();
-
-    fc.execute_string("10 0 DO I LOOP", GasLimit::Limited(250))?;
-
-    //fc.execute_string("1 IF 1 2 ADD ELSE 3 4 ADD THEN", GasLimit::Limited(100))?;
-    fc.execute_string("0 IF 1 2 ADD THEN", GasLimit::Limited(100))?;
-
-    println!("Contents of Number Stack {:?}", fc.sm.st.number_stack);
-    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
-
-    fc.execute_string(
-        ": RickTest 1 2 ADD 3 MUL ; RickTest",
-        GasLimit::Limited(100),
-    )?;
-
-    //    assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
-
-    fc.execute_string(": RickTest2 4 5 ADD 6 MUL ;", GasLimit::Limited(100))?;
-
-    fc.execute_string(
-        ": RickTest3 RickTest RickTest2 7 ADD 8 MUL ;",
-        GasLimit::Limited(100),
-    )?;
-
-    //    assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
-
-    fc.execute_string("RickTest3", GasLimit::Limited(100))?;
-
-    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 9, 9, 488]);
+const USAGE: &str = "Usage: rust-forth-compiler [--gas <LIMIT|unlimited>] [FILE...]
 
-    fc.execute_string("123 321 ADD 2 MUL", GasLimit::Limited(100))?;
+With no files an interactive REPL is started, otherwise each file is run in order
+through the same compiler and the final number stack is printed.
 
-    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 9, 9, 488, 888]);
+Options:
+  --gas <LIMIT|unlimited>  Gas available to each file or REPL line (default 10000000)
+  -O, --optimize           Inline small words, run compiled code through the peephole optimizer
+                           and turn tail calls into jumps
+  --check-stack-effects    Fail definitions whose code doesn't match their ( before -- after )
+                           comment
+  -h, --help               Print this help";
 
-    fc.execute_string("123 321 ADD 2 MUL", GasLimit::Limited(100))?;
-
-    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 9, 9, 488, 888, 888]);
-
-    let startup = fs::read_to_string("init.forth").expect("Test file init.forth not found");
-    fc.execute_string(&startup, GasLimit::Limited(100))?;
+struct Options {
+    gas_limit: GasLimit,
+    optimize: bool,
+    check_stack_effects: bool,
+    files: Vec<String>,
+}
 
-    fc.execute_string(
-        "predefined1 123 predefined2 456 POP Numbers MUL ADD DUP",
-        GasLimit::Limited(100),
-    )?;
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(1);
+        }
+    };
 
-    fc.execute_string(
-        ": RickCommand 123456 DUP ADD 777 ; RickCommand RickCommand",
-        GasLimit::Limited(100),
-    )?;
+    if !options.files.is_empty() {
+        process::exit(run_files(&options));
+    }
 
-    assert_eq!(
-        &fc.sm.st.number_stack,
-        &vec![
-            3_i64, 9, 9, 488, 888, 888, 123, 1, 2, 3, 34, 34, 246_912, 777, 246_912, 777
-        ]
+    println!(
+        "rust-forth-compiler {} (Ctrl-D to exit)",
+        env!("CARGO_PKG_VERSION")
     );
+    if let Err(err) = repl(options) {
+        eprintln!("Error reading input: {}", err);
+        process::exit(1);
+    }
+}
 
-    fc.sm.st.number_stack.push(123);
-    fc.sm.st.number_stack.push(321);
-    fc.sm.st.number_stack.push(0);
-    fc.execute_string("IF ADD 2 MUL ELSE ADD 3 MUL THEN", GasLimit::Limited(100))
-        .unwrap();
-    let n = fc.sm.st.number_stack.pop().unwrap();
-
-    assert_eq!(n, 888);
-
-    /*
-        let mut rf = ForthInterpreter::new();
-
-        let startup = fs::read_to_string("init.forth")?;
-        rf.execute_string(&startup)?;
-
-        rf.execute_string("predefined1 123 predefined2 456 POP Numbers MUL ADD DUP")?;
-
-        rf.execute_string(": RickCommand 123456 DUP ADD 777 ; RickCommand RickCommand")?;
-
-        assert_eq!(
-            rf.access_stack(),
-            &vec![123_i64, 1, 2, 3, 34, 34, 246912, 777, 246912, 777]
-        );
+// Returns None if help was asked for
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        gas_limit: GasLimit::Limited(DEFAULT_GAS_LIMIT),
+        optimize: false,
+        check_stack_effects: false,
+        files: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        } else if arg == "--gas" {
+            let value = args.next().ok_or("--gas needs a value")?;
+            options.gas_limit = parse_gas_limit(&value)?;
+        } else if let Some(value) = arg.strip_prefix("--gas=") {
+            options.gas_limit = parse_gas_limit(value)?;
+        } else if arg == "-O" || arg == "--optimize" {
+            options.optimize = true;
+        } else if arg == "--check-stack-effects" {
+            options.check_stack_effects = true;
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option: {}", arg));
+        } else {
+            options.files.push(arg);
+        }
+    }
 
-        rf.token_handlers
-            .push(Box::new(ExternalCommandHandler::new()));
+    Ok(Some(options))
+}
 
-        rf.execute_string("1111 123456 OUT 123456 IN")?;
+fn parse_gas_limit(value: &str) -> Result<GasLimit, String> {
+    if value.eq_ignore_ascii_case("unlimited") {
+        Ok(GasLimit::Unlimited)
+    } else {
+        value
+            .parse()
+            .map(GasLimit::Limited)
+            .map_err(|_| format!("Invalid gas limit: {}", value))
+    }
+}
 
-        assert_eq!(
-            rf.access_stack(),
-            &vec![123_i64, 1, 2, 3, 34, 34, 246912, 777, 246912, 777, 777]
-        );
+// Runs every file through one compiler, returning the exit code for the process
+fn run_files(options: &Options) -> i32 {
+    let mut fc = new_compiler(options);
 
-        rf.push_stack(123);
-        rf.push_stack(321);
-        rf.push_stack(0);
-        rf.execute_string("IF ADD 2 MUL ELSE ADD 3 MUL THEN")
-            .unwrap();
-        let n = rf.pop_stack().unwrap();
+    for file in options.files.iter() {
+        let source = match fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("{}: {}", file, err);
+                return 1;
+            }
+        };
+        if let Err(err) = fc.execute_string(&source, options.gas_limit.clone()) {
+            eprintln!("{}: {}", file, describe_error(&fc, &err));
+            if let Some(caret) = caret_for(&fc, &source) {
+                eprintln!("{}", caret);
+            }
+            if let Some(backtrace) = fc.last_backtrace() {
+                eprint!("{}", backtrace);
+            }
+            return err.into();
+        }
+    }
 
-        assert_eq!(n, 1332);
-    */
-    Ok(())
+    println!("Stack: {:?}", fc.sm.st.number_stack);
+    0
 }
-/*
-pub struct ExternalCommandHandler {}
-
-impl HandleToken for ExternalCommandHandler {
-    fn handle_token(&mut self, t: &Token, st: &mut State) -> Result<Handled, ForthError> {
-        if let Token::Command(s) = t {
-            println!("ExternalCommandHandler: Interpreting token {}", s);
-            match s.as_ref() {
-                "OUT" => self.out_port(st).map(|_| Ok(Handled::Handled))?,
-                "IN" => self.in_port(st).map(|_| Ok(Handled::Handled))?,
-                _ => Ok(Handled::NotHandled),
+
+fn repl(options: Options) -> Result<(), ReadlineError> {
+    let mut fc = new_compiler(&options);
+    let mut editor = DefaultEditor::new()?;
+    // Lines are collected here until any : definition they start has been closed with a ;
+    let mut pending = String::new();
+
+    loop {
+        let prompt = if pending.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                pending.push_str(&line);
+                pending.push('\n');
+                if definition_is_open(&pending) {
+                    continue;
+                }
+
+                editor.add_history_entry(pending.trim_end())?;
+                match fc.execute_string(&pending, options.gas_limit.clone()) {
+                    Ok(()) => println!("Stack: {:?}", fc.sm.st.number_stack),
+                    Err(err) => {
+                        println!("Error: {}", describe_error(&fc, &err));
+                        if let Some(caret) = caret_for(&fc, &pending) {
+                            println!("{}", caret);
+                        }
+                        if let Some(backtrace) = fc.last_backtrace() {
+                            print!("{}", backtrace);
+                        }
+                    }
+                }
+                pending.clear();
             }
-        } else {
-            Ok(Handled::NotHandled)
+            // Ctrl-C throws away a half entered definition, but keeps the session going
+            Err(ReadlineError::Interrupted) => pending.clear(),
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err),
         }
     }
 }
 
-impl ExternalCommandHandler {
-    fn out_port(&self, st: &mut State) -> Result<(), ForthError> {
-        let port = st.number_stack.pop_stack()?;
-        let value = st.number_stack.pop_stack()?;
-
-        println!("Sending {} to port {}", value, port);
-
-        Ok(())
+// A compiler set up the way the options ask for
+fn new_compiler(options: &Options) -> ForthCompiler {
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(options.optimize);
+    if options.optimize {
+        fc.set_inline_threshold(OPTIMIZE_INLINE_THRESHOLD);
     }
+    fc.set_check_stack_effects(options.check_stack_effects);
+    // Errors are shown with the words that were running
+    fc.set_backtraces(true);
+    fc
+}
 
-    fn in_port(&self, st: &mut State) -> Result<(), ForthError> {
-        let port = st.number_stack.pop_stack()?;
-        let value = 777;
-
-        st.number_stack.push_stack(value);
-
-        println!("Receiving {} from port {}", value, port);
-
-        Ok(())
+// The error, with where it happened if it failed to compile
+fn describe_error(fc: &ForthCompiler, err: &ForthError) -> String {
+    match fc.last_error_location() {
+        Some(location) => format!("{} at {}", err, location),
+        None => err.to_string(),
     }
+}
 
-    pub fn new() -> ExternalCommandHandler {
-        ExternalCommandHandler {}
-    }
+// The source line a compile error is on, with a caret under where it went wrong
+fn caret_for(fc: &ForthCompiler, source: &str) -> Option<String> {
+    fc.last_error_location()?.caret(source)
 }
-*/
@@ -0,0 +1,134 @@
+use rust_simple_stack_processor::Opcode;
+
+// A peephole optimizer for the opcodes compile_token_vector produces. It only ever replaces a
+// short run of opcodes with something that does the same thing in fewer, and never one that a
+// jump lands in the middle of. The relative jumps are then pointed at wherever their targets
+// have moved to.
+
+/// Optimize opcodes (and the source_map that goes with them) until nothing more can be done.
+/// Returns where each of the original opcodes ended up, with one extra entry for the end
+pub(crate) fn optimize(
+    opcodes: &mut Vec<Opcode>,
+    source_map: &mut Vec<Option<String>>,
+) -> Vec<usize> {
+    let mut moved: Vec<usize> = (0..=opcodes.len()).collect();
+    // Each pass can leave something new for the next one, like 1 2 ADD DROP
+    while let Some(pass) = optimize_once(opcodes, source_map) {
+        for location in moved.iter_mut() {
+            *location = pass[*location];
+        }
+    }
+    moved
+}
+
+// One pass over the opcodes, or None if it didn't find anything to do
+fn optimize_once(
+    opcodes: &mut Vec<Opcode>,
+    source_map: &mut Vec<Option<String>>,
+) -> Option<Vec<usize>> {
+    let jumps = relative_jumps(opcodes)?;
+    let mut is_target = vec![false; opcodes.len() + 1];
+    for &(_, target) in jumps.iter() {
+        is_target[target] = true;
+    }
+
+    let mut optimized = Vec::with_capacity(opcodes.len());
+    let mut optimized_source = Vec::with_capacity(opcodes.len());
+    let mut moved = Vec::with_capacity(opcodes.len() + 1);
+    let mut copied = vec![false; opcodes.len()];
+    let mut i = 0;
+    while i < opcodes.len() {
+        // Jumping to the start of a run is fine, it lands on the start of what replaces it
+        let rewrite = rewrite_at(&opcodes[i..])
+            .filter(|(length, _)| !is_target[i + 1..i + length].contains(&true));
+        match rewrite {
+            Some((length, replacement)) => {
+                moved.extend(std::iter::repeat_n(optimized.len(), length));
+                let source = source_map[i + length - 1].clone();
+                optimized_source.extend(std::iter::repeat_n(source, replacement.len()));
+                optimized.extend(replacement);
+                i += length;
+            }
+            None => {
+                moved.push(optimized.len());
+                optimized.push(opcodes[i].clone());
+                optimized_source.push(source_map[i].clone());
+                copied[i] = true;
+                i += 1;
+            }
+        }
+    }
+    moved.push(optimized.len());
+
+    // Every rewrite is shorter than what it replaces
+    if optimized.len() == opcodes.len() {
+        return None;
+    }
+
+    for (jump, target) in jumps {
+        if copied[jump] {
+            let offset = moved[target] as i64 - moved[jump] as i64;
+            optimized[moved[jump] - 1] = Opcode::LDI(offset);
+        }
+    }
+
+    *opcodes = optimized;
+    *source_map = optimized_source;
+    Some(moved)
+}
+
+// Where each JR, JRZ and JRNZ is, and where it goes to. These always have their offset pushed
+// by the LDI just before them, if one doesn't there is no telling where it goes, so give up
+fn relative_jumps(opcodes: &[Opcode]) -> Option<Vec<(usize, usize)>> {
+    let mut jumps = Vec::new();
+    for (jump, opcode) in opcodes.iter().enumerate() {
+        if matches!(opcode, Opcode::JR | Opcode::JRZ | Opcode::JRNZ) {
+            let Some(Opcode::LDI(offset)) = jump.checked_sub(1).map(|i| &opcodes[i]) else {
+                return None;
+            };
+            let target = usize::try_from(jump as i64 + offset)
+                .ok()
+                .filter(|&target| target <= opcodes.len())?;
+            jumps.push((jump, target));
+        }
+    }
+    Some(jumps)
+}
+
+// How many opcodes at the start of opcodes can be replaced, and what with
+fn rewrite_at(opcodes: &[Opcode]) -> Option<(usize, Vec<Opcode>)> {
+    if let [Opcode::LDI(a), Opcode::LDI(b), opcode, ..] = opcodes {
+        if let Some(result) = fold(*a, *b, opcode) {
+            return Some((3, vec![Opcode::LDI(result)]));
+        }
+    }
+    match opcodes {
+        [Opcode::LDI(_), Opcode::DROP, ..] => Some((2, vec![])),
+        [Opcode::LDI(a), Opcode::CMPZ, ..] => {
+            Some((2, vec![Opcode::LDI(if *a == 0 { -1 } else { 0 })]))
+        }
+        [Opcode::LDI(a), Opcode::CMPNZ, ..] => {
+            Some((2, vec![Opcode::LDI(if *a == 0 { 0 } else { -1 })]))
+        }
+        [Opcode::LDI(a), Opcode::NOT, ..] => {
+            Some((2, vec![Opcode::LDI(if *a == 0 { 1 } else { 0 })]))
+        }
+        // Jumping to the next opcode, like an IF with nothing before its THEN
+        [Opcode::LDI(1), Opcode::JR, ..] => Some((2, vec![])),
+        [Opcode::LDI(1), Opcode::JRZ | Opcode::JRNZ, ..] => Some((2, vec![Opcode::DROP])),
+        _ => None,
+    }
+}
+
+// What opcode leaves on the stack when a was pushed before b, unless it would be an error.
+// Errors are left to happen when the code runs
+fn fold(a: i64, b: i64, opcode: &Opcode) -> Option<i64> {
+    match opcode {
+        Opcode::ADD => a.checked_add(b),
+        Opcode::SUB => b.checked_sub(a),
+        Opcode::MUL => a.checked_mul(b),
+        Opcode::DIV => a.checked_div(b),
+        Opcode::AND => Some(a & b),
+        _ => None,
+    }
+}
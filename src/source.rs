@@ -0,0 +1,97 @@
+use rust_forth_tokenizer::{ForthToken, ForthTokenizer};
+use std::fmt;
+
+/// Where in the Forth source a compile error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    /// The position of the token in the source, counting every token (comments too) from 0
+    pub token: usize,
+    /// Counting from 1, or 0 if the source text wasn't available to work it out
+    pub line: usize,
+    /// Counting characters from 1, or 0 if the source text wasn't available to work it out
+    pub column: usize,
+    /// The word being defined, if the error is inside a definition
+    pub word: Option<String>,
+}
+
+impl SourceLocation {
+    pub(crate) fn new(token: usize, word: Option<String>) -> Self {
+        Self {
+            token,
+            line: 0,
+            column: 0,
+            word,
+        }
+    }
+
+    // Fill in the line and column from the source the tokens came from
+    pub(crate) fn resolve(&mut self, source: &str) {
+        if let Some(&offset) = token_offsets(source).get(self.token) {
+            let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+            self.line = source[..offset].matches('\n').count() + 1;
+            self.column = source[line_start..offset].chars().count() + 1;
+        }
+    }
+
+    /// The source line the error is on, with a caret under the start of the token
+    pub fn caret(&self, source: &str) -> Option<String> {
+        let line = source.lines().nth(self.line.checked_sub(1)?)?;
+        Some(format!(
+            "{}\n{}^",
+            line,
+            " ".repeat(self.column.saturating_sub(1))
+        ))
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "token {}", self.token)?;
+        } else {
+            write!(f, "line {}, column {}", self.line, self.column)?;
+        }
+        if let Some(word) = &self.word {
+            write!(f, ", in the definition of {}", word)?;
+        }
+        Ok(())
+    }
+}
+
+// The byte offset where each token from the tokenizer starts. The tokenizer doesn't tell us, so
+// this follows along behind it, using the slices it hands back where it can
+fn token_offsets(source: &str) -> Vec<usize> {
+    let base = source.as_ptr() as usize;
+    let end_of = |s: &str| s.as_ptr() as usize - base + s.len();
+    let mut offsets = Vec::new();
+    let mut cursor = 0;
+    for token in &ForthTokenizer::new(source) {
+        // Like the tokenizer, skip the whitespace before the token
+        let rest = &source[cursor..];
+        let start = cursor + rest.len() - rest.trim_start().len();
+        let end = match token {
+            ForthToken::Command(s) | ForthToken::DropLineComment(s) => end_of(s),
+            // Step over the closing ) or "
+            ForthToken::ParenthesizedRemark(s) | ForthToken::StringCommand(_, s) => end_of(s) + 1,
+            ForthToken::Number(_) => source[start..]
+                .find(|c: char| c.is_ascii_whitespace())
+                .map_or(source.len(), |length| start + length),
+            ForthToken::Colon | ForthToken::SemiColon => start + 1,
+        };
+        offsets.push(start);
+        cursor = end.min(source.len());
+    }
+    offsets
+}
+
+// How a token looked in the source, near enough, for showing in a backtrace
+pub(crate) fn token_text(token: &ForthToken) -> String {
+    match token {
+        ForthToken::Number(n) => n.to_string(),
+        ForthToken::Command(s) => (*s).to_owned(),
+        ForthToken::StringCommand(command, text) => format!("{} {}\"", command, text),
+        ForthToken::Colon => ":".to_owned(),
+        ForthToken::SemiColon => ";".to_owned(),
+        ForthToken::DropLineComment(s) | ForthToken::ParenthesizedRemark(s) => (*s).to_owned(),
+    }
+}
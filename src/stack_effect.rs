@@ -0,0 +1,228 @@
+use crate::syscall::{SYSCALL_ENTRY, Syscall};
+use rust_simple_stack_processor::Opcode;
+use std::fmt;
+
+/// How many items a word takes off the number stack, and how many it leaves there in their place
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl StackEffect {
+    // A ( before -- after ) remark, as the tokenizer hands it over with the ( still on the front.
+    // Remarks without exactly one -- aren't stack effects, and neither are ones with alternatives
+    // like ( x -- 0 | x x ), as they don't say how many items are left
+    pub(crate) fn parse(remark: &str) -> Option<StackEffect> {
+        let names: Vec<&str> = remark.trim_start_matches('(').split_whitespace().collect();
+        let (separators, items): (Vec<&str>, Vec<&str>) =
+            names.iter().partition(|name| **name == "--");
+        if separators.len() != 1 || !items.iter().all(|item| is_stack_item(item)) {
+            return None;
+        }
+        let separator = names.iter().position(|name| *name == "--")?;
+        Some(StackEffect {
+            inputs: separator,
+            outputs: names.len() - separator - 1,
+        })
+    }
+
+    // Whether code with this effect can be given the declared effect
+    fn fits(&self, declared: &StackEffect) -> bool {
+        self.inputs <= declared.inputs
+            && self.outputs as i64 - self.inputs as i64
+                == declared.outputs as i64 - declared.inputs as i64
+    }
+}
+
+// Items are names like n, n*n, c-addr or xt. Words with the punctuation of a sentence, or
+// quotes, mean the remark is prose that happens to have a -- in it
+fn is_stack_item(name: &str) -> bool {
+    name != "|" && !name.ends_with([',', '.', ':', ';']) && !name.contains(['"', '(', ')'])
+}
+
+impl fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "( {} -- {} )", self.inputs, self.outputs)
+    }
+}
+
+// What the code of a word was found to do to the number stack
+#[derive(Debug, PartialEq)]
+pub(crate) enum Analysis {
+    Effect(StackEffect),
+    // Different paths through the code leave different numbers of items
+    Inconsistent,
+    // It does something we can't follow, like EXECUTE, or calling a word with no known effect
+    Unknown,
+}
+
+impl Analysis {
+    // The error if the code doesn't do what was declared, None if it does or there is no knowing
+    pub(crate) fn mismatch(&self, declared: &StackEffect) -> Option<Option<StackEffect>> {
+        match self {
+            Analysis::Effect(effect) if !effect.fits(declared) => Some(Some(*effect)),
+            Analysis::Inconsistent => Some(None),
+            _ => None,
+        }
+    }
+}
+
+// Follow the stack depth through every path in a compiled word, relative to where it started.
+// effect_at gives the effect of the word starting at an address, for calls
+pub(crate) fn analyze(
+    opcodes: &[Opcode],
+    effect_at: impl Fn(i64) -> Option<StackEffect>,
+) -> Analysis {
+    let mut depth_at: Vec<Option<i64>> = vec![None; opcodes.len()];
+    let mut to_visit = vec![(0, 0)];
+    let mut lowest = 0;
+    let mut end_depth = None;
+
+    while let Some((address, depth)) = to_visit.pop() {
+        let Some(known) = depth_at.get_mut(address) else {
+            return Analysis::Unknown;
+        };
+        match known {
+            Some(known) if *known == depth => continue,
+            Some(_) => return Analysis::Inconsistent,
+            None => *known = Some(depth),
+        }
+
+        let Some(step) = step(opcodes, address, &effect_at) else {
+            return Analysis::Unknown;
+        };
+        lowest = lowest.min(depth - step.pops as i64);
+        let depth = depth - step.pops as i64 + step.pushes as i64;
+        match step.next {
+            Next::Return => match end_depth {
+                Some(end) if end != depth => return Analysis::Inconsistent,
+                _ => end_depth = Some(depth),
+            },
+            Next::Address(next) => to_visit.push((next, depth)),
+            Next::Branch(next, target) => {
+                to_visit.push((next, depth));
+                match usize::try_from(target) {
+                    Ok(target) => to_visit.push((target, depth)),
+                    Err(_) => return Analysis::Unknown,
+                }
+            }
+        }
+    }
+
+    // A word that never returns has no effect to check
+    match end_depth {
+        Some(end) => Analysis::Effect(StackEffect {
+            inputs: (-lowest) as usize,
+            outputs: (end - lowest) as usize,
+        }),
+        None => Analysis::Unknown,
+    }
+}
+
+struct Step {
+    pops: usize,
+    pushes: usize,
+    next: Next,
+}
+
+enum Next {
+    Address(usize),
+    // Carries on at the first address, or jumps to the second
+    Branch(usize, i64),
+    Return,
+}
+
+// What the opcodes at address do to the stack, and where they go next. Opcodes that the compiler
+// always puts together are taken together, as the LDI on its own would throw the depth out
+fn step(
+    opcodes: &[Opcode],
+    address: usize,
+    effect_at: &impl Fn(i64) -> Option<StackEffect>,
+) -> Option<Step> {
+    let simple = |length: usize, pops: usize, pushes: usize| Step {
+        pops,
+        pushes,
+        next: Next::Address(address + length),
+    };
+    Some(match &opcodes[address..] {
+        [Opcode::LDI(id), Opcode::LDI(entry), Opcode::CALL, ..]
+            if *entry == SYSCALL_ENTRY as i64 =>
+        {
+            let (pops, pushes) = match Syscall::from_id(*id)? {
+                Syscall::Depth => (0, 1),
+                // Each of these pops a count, how far down they reach depends on it
+                Syscall::Pick => (1, 1),
+                Syscall::Roll => (1, 0),
+                Syscall::LShift | Syscall::RShift => (2, 1),
+                Syscall::Dot | Syscall::Emit => (1, 0),
+                // ." pushes the bytes then how many there are, straight before the syscall
+                Syscall::PrintString => match address.checked_sub(1).map(|i| &opcodes[i]) {
+                    Some(Opcode::LDI(count)) => (usize::try_from(*count).ok()? + 1, 0),
+                    _ => return None,
+                },
+                Syscall::UndefinedWord => return None,
+            };
+            simple(3, pops, pushes)
+        }
+        [Opcode::LDI(target), Opcode::CALL, ..] => {
+            let effect = effect_at(*target)?;
+            simple(2, effect.inputs, effect.outputs)
+        }
+        // A tail call, the word called returns for us
+        [Opcode::LDI(target), Opcode::JMP, ..] => {
+            let effect = effect_at(*target)?;
+            Step {
+                pops: effect.inputs,
+                pushes: effect.outputs,
+                next: Next::Return,
+            }
+        }
+        // Relative jumps go from the opcode after the LDI
+        [Opcode::LDI(offset), Opcode::JR, ..] => Step {
+            pops: 0,
+            pushes: 0,
+            next: Next::Address(usize::try_from(address as i64 + 1 + offset).ok()?),
+        },
+        [Opcode::LDI(offset), Opcode::JRZ | Opcode::JRNZ, ..] => Step {
+            pops: 1,
+            pushes: 0,
+            next: Next::Branch(address + 2, address as i64 + 1 + offset),
+        },
+        [Opcode::LDI(count), Opcode::MOVETOCELLS, ..] => {
+            simple(2, usize::try_from(*count).ok()? + 1, 0)
+        }
+        [Opcode::LDI(count), Opcode::MOVEFROMCELLS, ..] => {
+            simple(2, 1, usize::try_from(*count).ok()?)
+        }
+        // ?DUP leaves one or two items depending on the value, so there is no telling
+        [Opcode::DUP, Opcode::LDI(2), Opcode::JRZ, Opcode::DUP, ..] => return None,
+        [Opcode::RET, ..] => Step {
+            pops: 0,
+            pushes: 0,
+            next: Next::Return,
+        },
+        [opcode, ..] => {
+            let (pops, pushes) = match opcode {
+                Opcode::LDI(_) | Opcode::GETLP | Opcode::GETLP2 | Opcode::CMPLOOP => (0, 1),
+                Opcode::RGt | Opcode::RAt => (0, 1),
+                Opcode::RGt2 | Opcode::RAt2 => (0, 2),
+                Opcode::DROP | Opcode::GtR | Opcode::ADDLP | Opcode::NEWCELLS => (1, 0),
+                Opcode::GtR2 | Opcode::PUSHLP => (2, 0),
+                Opcode::CMPZ | Opcode::CMPNZ | Opcode::NOT => (1, 1),
+                Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::AND => (2, 1),
+                Opcode::DUP => (1, 2),
+                Opcode::SWAP => (2, 2),
+                Opcode::DUP2 => (2, 4),
+                Opcode::SWAP2 => (4, 4),
+                Opcode::OVER2 => (4, 6),
+                Opcode::NOP | Opcode::INCLP | Opcode::DROPLP => (0, 0),
+                // Jumps and calls to somewhere only known at runtime, TRAPs to handlers that
+                // could do anything, and cell moves of an unknown number of cells
+                _ => return None,
+            };
+            simple(1, pops, pushes)
+        }
+        [] => return None,
+    })
+}
@@ -0,0 +1,104 @@
+use rust_simple_stack_processor::{
+    HandleTrap, Opcode, StackMachineError, StackMachineState, TrapHandled,
+};
+use std::cell::Cell;
+use std::rc::Rc;
+
+// Syscalls are made by CALLing this entry point with the syscall id on the stack, it loads
+// SYSCALL_TRAP_ID and TRAPs, then RETs. A handled TRAP stops the StackMachine, so once the compiler
+// has serviced the syscall it restarts the StackMachine on the RET, which returns to the caller.
+pub(crate) const SYSCALL_ENTRY: usize = 0;
+pub(crate) const SYSCALL_RESUME: usize = SYSCALL_ENTRY + 2;
+
+// The one trap id the compiler keeps for itself, every other id is left to the user's trap handlers
+pub(crate) const SYSCALL_TRAP_ID: i64 = i64::MIN;
+
+/// These are the words the compiler implements itself, because they can't be built out of Opcodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Syscall {
+    Depth,
+    Pick,
+    Roll,
+    LShift,
+    RShift,
+    Dot,
+    Emit,
+    PrintString,
+    UndefinedWord,
+}
+
+impl Syscall {
+    pub(crate) fn id(self) -> i64 {
+        match self {
+            Syscall::Depth => -1,
+            Syscall::Pick => -2,
+            Syscall::Roll => -3,
+            Syscall::LShift => -4,
+            Syscall::RShift => -5,
+            Syscall::Dot => -6,
+            Syscall::Emit => -7,
+            Syscall::PrintString => -8,
+            Syscall::UndefinedWord => -9,
+        }
+    }
+
+    pub(crate) fn from_id(id: i64) -> Option<Syscall> {
+        match id {
+            -1 => Some(Syscall::Depth),
+            -2 => Some(Syscall::Pick),
+            -3 => Some(Syscall::Roll),
+            -4 => Some(Syscall::LShift),
+            -5 => Some(Syscall::RShift),
+            -6 => Some(Syscall::Dot),
+            -7 => Some(Syscall::Emit),
+            -8 => Some(Syscall::PrintString),
+            -9 => Some(Syscall::UndefinedWord),
+            _ => None,
+        }
+    }
+
+    // The opcodes that make this syscall
+    pub(crate) fn opcodes(self) -> Vec<Opcode> {
+        vec![
+            Opcode::LDI(self.id()),
+            Opcode::LDI(SYSCALL_ENTRY as i64),
+            Opcode::CALL,
+        ]
+    }
+}
+
+// The opcodes that live at SYSCALL_ENTRY
+pub(crate) fn syscall_entry() -> Vec<Opcode> {
+    vec![Opcode::LDI(SYSCALL_TRAP_ID), Opcode::TRAP, Opcode::RET]
+}
+
+// This catches the syscall trap and remembers which syscall was made, so the compiler can service
+// it after the StackMachine stops
+pub(crate) struct SyscallTrapHandler {
+    pending_syscall: Rc<Cell<Option<Syscall>>>,
+}
+
+impl SyscallTrapHandler {
+    pub(crate) fn new(pending_syscall: Rc<Cell<Option<Syscall>>>) -> Self {
+        Self { pending_syscall }
+    }
+}
+
+impl HandleTrap for SyscallTrapHandler {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        if trap_id != SYSCALL_TRAP_ID {
+            return Ok(TrapHandled::NotHandled);
+        }
+        if let Some(syscall) = st.number_stack.last().copied().and_then(Syscall::from_id) {
+            st.number_stack.pop();
+            self.pending_syscall.set(Some(syscall));
+            Ok(TrapHandled::Handled)
+        } else {
+            Ok(TrapHandled::NotHandled)
+        }
+    }
+}
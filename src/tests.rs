@@ -385,6 +385,185 @@ fn test_do_loop_compound_run_1() {
     );
 }
 
+#[test]
+fn test_do_loop_exit() {
+    let tokenizer = ForthTokenizer::new(": FIRST 10 0 DO 5 0 DO I EXIT LOOP LOOP ;");
+    let mut fc = ForthCompiler::default();
+    fc.compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    let start = *fc.word_addresses.get("FIRST").unwrap();
+    assert_eq!(
+        &fc.sm.st.opcodes[start..start + 10],
+        &[
+            Opcode::LDI(10),
+            Opcode::LDI(0),
+            Opcode::PUSHLP,
+            Opcode::LDI(5),
+            Opcode::LDI(0),
+            Opcode::PUSHLP,
+            Opcode::GETLP,
+            Opcode::DROPLP,
+            Opcode::DROPLP,
+            Opcode::RET,
+        ]
+    );
+}
+
+#[test]
+fn test_do_loop_exit_run() {
+    let mut fc = ForthCompiler::default();
+
+    // The loop stack has to be empty again after leaving both loops early, so I fails
+    match fc.execute_string(
+        ": FIND-3 10 0 DO 10 0 DO J I MUL 3 = IF J I EXIT THEN LOOP LOOP 0 ; FIND-3 I",
+        GasLimit::Limited(1000),
+    ) {
+        Err(ForthError::LoopStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 3]);
+}
+
+#[test]
+fn test_do_loop_exit_caller_loop_run() {
+    let mut fc = ForthCompiler::default();
+
+    // Leaving the inner word's loop mustn't disturb the loop that called it
+    fc.execute_string(
+        ": FIRST-EVEN 10 0 DO I 2 MOD 0= I 0> AND IF I EXIT THEN LOOP -1 ; 3 0 DO FIRST-EVEN I LOOP",
+        GasLimit::Limited(2000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![2_i64, 0, 2, 1, 2, 2]);
+}
+
+#[test]
+fn test_exit_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": SIGN DUP 0< IF DROP -1 EXIT THEN 0> IF 1 EXIT THEN 0 ; -5 SIGN 0 SIGN 5 SIGN",
+        GasLimit::Limited(500),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64, 0, 1]);
+}
+
+#[test]
+fn test_question_do_loop() {
+    let tokenizer = ForthTokenizer::new("10 0 ?DO I LOOP");
+    let mut fc = ForthCompiler::default();
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI(10),
+            Opcode::LDI(0),
+            Opcode::DUP2,
+            Opcode::SUB,
+            Opcode::LDI(5),
+            Opcode::JRNZ,
+            Opcode::DROP,
+            Opcode::DROP,
+            Opcode::LDI(8),
+            Opcode::JR,
+            Opcode::PUSHLP,
+            Opcode::GETLP,
+            Opcode::INCLP,
+            Opcode::CMPLOOP,
+            Opcode::LDI(-4),
+            Opcode::JRZ,
+            Opcode::DROPLP,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_question_do_loop_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("5 0 ?DO I LOOP 10 0 ?DO I 3 +LOOP", GasLimit::Limited(250))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 2, 3, 4, 0, 3, 6, 9]);
+}
+
+#[test]
+fn test_question_do_loop_empty_range_run() {
+    let mut fc = ForthCompiler::default();
+
+    // Neither loop body runs, and nothing is left on the number or loop stacks
+    fc.execute_string(
+        "1 10 10 ?DO I LOOP 2 0 0 ?DO I 2 +LOOP 3",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2, 3]);
+    match fc.execute_string("I", GasLimit::Limited(100)) {
+        Err(ForthError::LoopStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_question_do_loop_empty_range_in_word_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": SUM 0 SWAP 0 ?DO I ADD LOOP ; 0 SUM 4 SUM",
+        GasLimit::Limited(250),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 6]);
+}
+
+#[test]
+fn test_unloop_exit_run() {
+    let mut fc = ForthCompiler::default();
+
+    // UNLOOP EXIT drops each loop once, the outer loop is still there for I afterwards
+    fc.execute_string(
+        ": FIRST-OVER-5 10 0 DO I 5 > IF I UNLOOP EXIT THEN LOOP -1 ; : FIRST-OVER-12 20 0 DO I 12 > IF I UNLOOP EXIT THEN LOOP -1 ; 2 0 DO FIRST-OVER-5 FIRST-OVER-12 I LOOP",
+        GasLimit::Limited(2000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![6_i64, 13, 0, 6, 13, 1]);
+}
+
+#[test]
+fn test_unloop_nested_exit_run() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(
+        ": FIND-3 10 0 DO 10 0 DO J I MUL 3 = IF J I UNLOOP UNLOOP EXIT THEN LOOP LOOP 0 ; FIND-3 I",
+        GasLimit::Limited(1000),
+    ) {
+        Err(ForthError::LoopStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 3]);
+}
+
+#[test]
+fn test_unloop_without_do_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(": BROKEN UNLOOP EXIT ;", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
 #[test]
 fn test_begin_while_repeat() {
     let tokenizer = ForthTokenizer::new("BEGIN 123 WHILE 456 REPEAT");
@@ -829,6 +1008,32 @@ fn test_trap_4() {
     assert_eq!(&fc.sm.st.number_stack, &vec![654321]);
 }
 
+#[test]
+fn test_trap_reserved_id() {
+    let mut fc = ForthCompiler::default();
+
+    // Syscalls are numbered with negative ids, but a TRAP the program runs itself is still just a TRAP
+    match fc.execute_string("5 -7 TRAP 7 8", GasLimit::Limited(100)) {
+        Err(ForthError::UnhandledTrap {
+            unhandled_trap_id: -7,
+        }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    fc.sm
+        .trap_handlers
+        .push(Box::from(TrapHandler::new(-7, |_trap_id, st| {
+            st.number_stack.push(42);
+            Ok(TrapHandled::Handled)
+        })));
+    fc.sm.st.number_stack.clear();
+    fc.execute_string("5 -7 TRAP 7 8", GasLimit::Limited(100))
+        .unwrap();
+
+    // The user's handler gets it, and a handled TRAP stops the run as it always has
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 42]);
+}
+
 #[test]
 fn test_intrinsics_eq_run_1() {
     let mut fc = ForthCompiler::default();
@@ -864,3 +1069,3175 @@ fn test_intrinsics_ne_run_2() {
 
     assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
 }
+
+#[test]
+fn test_trailing_whitespace_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": SQUARE DUP MUL ;\n", GasLimit::Limited(100))
+        .unwrap();
+    fc.execute_string("7 SQUARE ", GasLimit::Limited(100))
+        .unwrap();
+    fc.execute_string("1 ( trailing remark )", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![49_i64, 1]);
+}
+
+#[test]
+fn test_intrinsics_lt_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 <", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_lt_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("2 1 <", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_lt_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("2 2 <", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_lt_run_4() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-5 3 <", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_gt_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("2 1 >", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_gt_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 >", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_gt_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("2 2 >", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_gt_run_4() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("3 -5 >", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_le_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 <=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_le_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("2 2 <=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_le_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("3 2 <=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_ge_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("2 1 >=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_ge_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("2 2 >=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_ge_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 >=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_eq_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("0 0=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_eq_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("7 0=", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_lt_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-3 0<", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_lt_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("0 0<", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_lt_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("3 0<", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_gt_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("3 0>", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_gt_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("0 0>", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_zero_gt_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-3 0>", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_lt() {
+    let tokenizer = ForthTokenizer::new("1 2 <");
+    let mut fc = ForthCompiler::default();
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::SWAP,
+            Opcode::PUSHLP,
+            Opcode::CMPLOOP,
+            Opcode::DROPLP,
+            Opcode::CMPZ,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_intrinsics_compare_in_loop_run() {
+    let mut fc = ForthCompiler::default();
+
+    // The comparisons borrow the loop stack, so I has to be unaffected afterwards
+    fc.execute_string("5 0 DO I 3 < IF I THEN LOOP", GasLimit::Limited(250))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 2]);
+}
+
+#[test]
+fn test_intrinsics_over() {
+    let tokenizer = ForthTokenizer::new("1 2 OVER");
+    let mut fc = ForthCompiler::default();
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::GtR,
+            Opcode::DUP,
+            Opcode::RGt,
+            Opcode::SWAP,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_intrinsics_over_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 OVER", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2, 1]);
+}
+
+#[test]
+fn test_intrinsics_rot_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 3 ROT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![2_i64, 3, 1]);
+}
+
+#[test]
+fn test_intrinsics_minus_rot_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 3 -ROT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 1, 2]);
+}
+
+#[test]
+fn test_intrinsics_nip_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 3 NIP", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 3]);
+}
+
+#[test]
+fn test_intrinsics_tuck_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 3 TUCK", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 3, 2, 3]);
+}
+
+#[test]
+fn test_intrinsics_question_dup_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 2 ?DUP", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2, 2]);
+}
+
+#[test]
+fn test_intrinsics_question_dup_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 0 ?DUP", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 0]);
+}
+
+#[test]
+fn test_intrinsics_pick_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("10 20 30 0 PICK 3 PICK", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![10_i64, 20, 30, 30, 10]);
+}
+
+#[test]
+fn test_intrinsics_pick_underflow() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("10 20 2 PICK", GasLimit::Limited(100)) {
+        Err(ForthError::NumberStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_intrinsics_roll_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("10 20 30 40 2 ROLL", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![10_i64, 30, 40, 20]);
+}
+
+#[test]
+fn test_intrinsics_depth_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("DEPTH 7 7 DEPTH", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 7, 7, 3]);
+}
+
+#[test]
+fn test_intrinsics_depth_in_word_run() {
+    let mut fc = ForthCompiler::default();
+
+    // Syscalls have to return to the right place inside nested words and loops
+    fc.execute_string(
+        ": STACKED DEPTH ; : LOOPED 3 0 DO STACKED LOOP ; LOOPED 99",
+        GasLimit::Limited(250),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 2, 99]);
+}
+
+#[test]
+fn test_syscall_gas_limit() {
+    let mut fc = ForthCompiler::default();
+
+    // Gas is counted across every syscall in a run, not just since the last one
+    match fc.execute_string("BEGIN DEPTH DROP 0 UNTIL", GasLimit::Limited(100)) {
+        Err(ForthError::RanOutOfGas {
+            gas_used: 101,
+            gas_limit: GasLimit::Limited(100),
+        }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_intrinsics_or_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("12 10 OR", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![14_i64]);
+}
+
+#[test]
+fn test_intrinsics_or_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-1 0 OR", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_xor_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("12 10 XOR", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![6_i64]);
+}
+
+#[test]
+fn test_intrinsics_xor_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-1 5 XOR", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-6_i64]);
+}
+
+#[test]
+fn test_intrinsics_invert_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("0 INVERT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_invert_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-9223372036854775808 INVERT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![9223372036854775807_i64]);
+}
+
+#[test]
+fn test_intrinsics_lshift_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 4 LSHIFT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![16_i64]);
+}
+
+#[test]
+fn test_intrinsics_lshift_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 64 LSHIFT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_rshift_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("256 4 RSHIFT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![16_i64]);
+}
+
+#[test]
+fn test_intrinsics_rshift_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-1 60 RSHIFT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![15_i64]);
+}
+
+#[test]
+fn test_intrinsics_mod_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("7 3 MOD", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64]);
+}
+
+#[test]
+fn test_intrinsics_mod_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-7 3 MOD", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64]);
+}
+
+#[test]
+fn test_intrinsics_slash_mod_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("7 3 /MOD", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2]);
+}
+
+#[test]
+fn test_intrinsics_slash_mod_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-7 2 /MOD", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64, -3]);
+}
+
+#[test]
+fn test_intrinsics_negate_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("5 NEGATE", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-5_i64]);
+}
+
+#[test]
+fn test_intrinsics_negate_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-5 NEGATE", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+}
+
+#[test]
+fn test_intrinsics_abs_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-5 ABS", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+}
+
+#[test]
+fn test_intrinsics_abs_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("5 ABS", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+}
+
+#[test]
+fn test_intrinsics_abs_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("0 ABS", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_intrinsics_min_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("3 5 MIN", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+}
+
+#[test]
+fn test_intrinsics_min_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("5 3 MIN", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+}
+
+#[test]
+fn test_intrinsics_min_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-5 3 MIN", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-5_i64]);
+}
+
+#[test]
+fn test_intrinsics_max_run_1() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("3 5 MAX", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+}
+
+#[test]
+fn test_intrinsics_max_run_2() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("5 3 MAX", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+}
+
+#[test]
+fn test_intrinsics_max_run_3() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("-5 3 MAX", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+}
+
+#[test]
+fn test_intrinsics_numeric_fail_1() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    match fc.execute_string("1 0 DIV", GasLimit::Limited(100)) {
+        Err(ForthError::DivisionByZero { opcode, address }) => {
+            assert_eq!(opcode, Opcode::DIV);
+            assert_eq!(address, Some(FIRST_WORD_ADDRESS + 2));
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_intrinsics_numeric_fail_2() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("1 0 MOD", GasLimit::Limited(100)) {
+        Err(ForthError::DivisionByZero { .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_intrinsics_numeric_fail_3() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("1 0 /MOD", GasLimit::Limited(100)) {
+        Err(ForthError::DivisionByZero { .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_intrinsics_numeric_fail_4() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("-9223372036854775808 NEGATE", GasLimit::Limited(100)) {
+        Err(ForthError::NumericOverflow { opcode, .. }) => assert_eq!(opcode, Opcode::SUB),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_intrinsics_numeric_fail_5() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    // The quotient doesn't fit, but nothing is being divided by zero
+    for source in [
+        "-9223372036854775808 -1 DIV",
+        "-9223372036854775808 -1 MOD",
+        "-9223372036854775808 -1 /MOD",
+    ] {
+        match fc.execute_string(source, GasLimit::Limited(100)) {
+            Err(ForthError::NumericOverflow { opcode, address }) => {
+                assert_eq!(opcode, Opcode::DIV);
+                assert!(address.is_some());
+            }
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_variable() {
+    let tokenizer = ForthTokenizer::new("VARIABLE x 5 x !");
+    let mut fc = ForthCompiler::default();
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    let x = *fc.word_addresses.get("x").unwrap() as i64;
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI(1),
+            Opcode::NEWCELLS,
+            Opcode::LDI(5),
+            Opcode::LDI(x),
+            Opcode::CALL,
+            Opcode::LDI(1),
+            Opcode::MOVETOCELLS,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_variable_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("VARIABLE x VARIABLE y 5 x ! 7 y !", GasLimit::Limited(100))
+        .unwrap();
+    fc.execute_string("x @ y @ 3 x +! x @", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 7, 8]);
+}
+
+#[test]
+fn test_variable_in_word_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        "VARIABLE counter 0 counter ! : BUMP 1 counter +! ; BUMP BUMP BUMP counter @",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+}
+
+#[test]
+fn test_constant_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        "10 CONSTANT ten 6 7 MUL CONSTANT answer",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+    fc.execute_string(
+        ": TEN-MORE ten ADD ; answer TEN-MORE",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![52_i64]);
+}
+
+#[test]
+fn test_value_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        "1 VALUE level : LEVEL-UP level 1+ TO level ; LEVEL-UP LEVEL-UP level",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+    fc.execute_string("10 TO level level", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 10]);
+}
+
+#[test]
+fn test_to_constant_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("10 CONSTANT ten 11 TO ten", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_variable_missing_name_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("VARIABLE 10", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+// Output sink for tests, the compiler writes through one clone while the test reads the other
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl std::io::Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_dot_run() {
+    let mut fc = ForthCompiler::default();
+    let output = SharedOutput::default();
+    fc.set_output(output.clone());
+
+    fc.execute_string("1 2 ADD . -7 . 99", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(output.contents(), "3 -7 ");
+    assert_eq!(&fc.sm.st.number_stack, &vec![99_i64]);
+}
+
+#[test]
+fn test_emit_cr_space_run() {
+    let mut fc = ForthCompiler::default();
+    let output = SharedOutput::default();
+    fc.set_output(output.clone());
+
+    fc.execute_string("72 EMIT 105 EMIT SPACE 33 EMIT CR", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(output.contents(), "Hi !\n");
+}
+
+#[test]
+fn test_dot_quote() {
+    let tokenizer = ForthTokenizer::new(".\" Hi\" 1");
+    let mut fc = ForthCompiler::default();
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI('H' as i64),
+            Opcode::LDI('i' as i64),
+            Opcode::LDI(2),
+            Opcode::LDI(Syscall::PrintString.id()),
+            Opcode::LDI(0),
+            Opcode::CALL,
+            Opcode::LDI(1),
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_dot_quote_run() {
+    let mut fc = ForthCompiler::default();
+    let output = SharedOutput::default();
+    fc.set_output(output.clone());
+
+    fc.execute_string(
+        ": GREET .\" Hello, \" . CR ; 3 0 DO I GREET LOOP 42 .\" wörld\"",
+        GasLimit::Limited(500),
+    )
+    .unwrap();
+
+    assert_eq!(output.contents(), "Hello, 0 \nHello, 1 \nHello, 2 \nwörld");
+    // The string bytes must not be left behind on the stack
+    assert_eq!(&fc.sm.st.number_stack, &vec![42_i64]);
+}
+
+#[test]
+fn test_dot_underflow() {
+    let mut fc = ForthCompiler::default();
+    fc.set_output(SharedOutput::default());
+
+    match fc.execute_string(".", GasLimit::Limited(100)) {
+        Err(ForthError::NumberStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_unknown_string_command_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("S\" text\"", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(token)) => assert_eq!(token, "S\""),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_redefine_keeps_old_callers_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": A 1 ; : B A ; : A 2 ; B A", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2]);
+}
+
+#[test]
+fn test_redefine_reuses_memory() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": FOO 1 2 ADD ;", GasLimit::Limited(100))
+        .unwrap();
+    let first_end = fc.last_function;
+
+    for n in 0..100 {
+        fc.execute_string(&format!(": FOO {} 2 ADD ; FOO", n), GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(fc.sm.st.number_stack.pop(), Some(n + 2));
+        // At most the new definition, and the gap the previous one left behind
+        assert!(fc.last_function <= first_end + 4);
+    }
+    assert_eq!(fc.dictionary.len(), 1);
+}
+
+#[test]
+fn test_reload_library_bounded_memory() {
+    let mut fc = ForthCompiler::default();
+    let library = "VARIABLE total : SQUARE DUP MUL ; : SUM-SQUARES SQUARE SWAP SQUARE ADD ; : STORE-SUM SUM-SQUARES total ! ;";
+
+    fc.execute_string(library, GasLimit::Limited(100)).unwrap();
+    fc.execute_string(library, GasLimit::Limited(100)).unwrap();
+    let high_water = fc.last_function;
+
+    for n in 0..100 {
+        fc.execute_string(library, GasLimit::Limited(100)).unwrap();
+        fc.execute_string(
+            &format!("{} 4 STORE-SUM total @", n),
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(fc.sm.st.number_stack.pop(), Some(n * n + 16));
+        assert!(fc.last_function <= high_water);
+        assert!(fc.sm.st.opcodes.len() <= high_water + 10);
+    }
+}
+
+#[test]
+fn test_forget_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": A 1 ;", GasLimit::Limited(100))
+        .unwrap();
+    let after_a = fc.last_function;
+    fc.execute_string(": B 2 ; : C B 3 ; FORGET B A", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64]);
+    assert_eq!(fc.last_function, after_a);
+    assert!(!fc.word_addresses.contains_key("B"));
+    assert!(!fc.word_addresses.contains_key("C"));
+
+    match fc.execute_string("C", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(token)) => assert_eq!(token, "C"),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_forget_redefined_run() {
+    let mut fc = ForthCompiler::default();
+
+    // USE-A keeps the first A's code alive, but the name A is forgotten all the same
+    fc.execute_string(
+        ": A 1 ; : USE-A A ; : A 2 ; FORGET A USE-A",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64]);
+
+    match fc.execute_string("A", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(token)) => assert_eq!(token, "A"),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_forget_redefined_unused_run() {
+    let mut fc = ForthCompiler::default();
+
+    // Nothing calls the first A, which makes no difference to what FORGET does
+    match fc.execute_string(": A 1 ; : A 2 ; FORGET A A", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(token)) => assert_eq!(token, "A"),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_forget_redefined_before_marker_run() {
+    let mut fc = ForthCompiler::default();
+
+    // Words from before a MARKER are always kept, so the first A comes back
+    fc.execute_string(
+        ": A 1 ; MARKER M : A 2 ; FORGET A A",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64]);
+}
+
+#[test]
+fn test_forget_after_use_run() {
+    let mut fc = ForthCompiler::default();
+
+    // The code before FORGET runs before A is forgotten
+    fc.execute_string(": A 5 ; A FORGET A", GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+
+    fc.execute_string("MARKER M : A 1 ; A M", GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 1]);
+    assert!(!fc.word_addresses.contains_key("A"));
+}
+
+#[test]
+fn test_forget_shares_gas_limit() {
+    let mut fc = ForthCompiler::default();
+
+    // Each half fits in the limit, but not both
+    match fc.execute_string(": A 1 ; 1 1 1 1 1 FORGET A 1 1 1 1 1", GasLimit::Limited(8)) {
+        Err(ForthError::RanOutOfGas {
+            gas_limit: GasLimit::Limited(8),
+            ..
+        }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(&fc.sm.st.number_stack[..5], &[1_i64; 5]);
+}
+
+#[test]
+fn test_forget_unknown_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("FORGET NOTHING", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(token)) => assert_eq!(token, "NOTHING"),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_marker_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": KEEP 7 ;", GasLimit::Limited(100))
+        .unwrap();
+    let before_module = fc.last_function;
+    let words_before_module = fc.word_addresses.len();
+
+    fc.execute_string(
+        "MARKER -module VARIABLE scratch : HELPER 2 ; : KEEP HELPER scratch ! ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+    fc.execute_string("KEEP scratch @", GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(&fc.sm.st.number_stack, &vec![2_i64]);
+
+    // Backing the module out brings back the KEEP it replaced
+    fc.execute_string("-module KEEP", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![2_i64, 7]);
+    assert_eq!(fc.last_function, before_module);
+    assert_eq!(fc.word_addresses.len(), words_before_module);
+    assert!(!fc.word_addresses.contains_key("-module"));
+}
+
+#[test]
+fn test_recurse() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": FOREVER RECURSE ;", GasLimit::Limited(100))
+        .unwrap();
+
+    let start = FIRST_WORD_ADDRESS;
+    assert_eq!(
+        &fc.sm.st.opcodes[start..fc.last_function],
+        &[Opcode::LDI(start as i64), Opcode::CALL, Opcode::RET]
+    );
+}
+
+#[test]
+fn test_recurse_factorial_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": FACT DUP 1 > IF DUP 1- RECURSE MUL THEN ; 10 FACT 1 FACT",
+        GasLimit::Limited(500),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_628_800_i64, 1]);
+}
+
+#[test]
+fn test_self_reference_fibonacci_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": FIB DUP 2 < IF ELSE DUP 1- FIB SWAP 2- FIB ADD THEN ; 15 FIB",
+        GasLimit::Limited(100_000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![610_i64]);
+}
+
+#[test]
+fn test_self_reference_earlier_definition_run() {
+    let mut fc = ForthCompiler::default();
+
+    // With an earlier definition around, the name means the earlier one like standard Forth
+    fc.execute_string(
+        ": TWICE 2 MUL ; : TWICE TWICE TWICE ; 3 TWICE",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![12_i64]);
+}
+
+#[test]
+fn test_recurse_in_reused_memory_run() {
+    let mut fc = ForthCompiler::default();
+
+    // The second COUNTDOWN goes into the gap the first one leaves, and has to call itself there
+    fc.execute_string(
+        ": COUNTDOWN DUP IF DUP 1- RECURSE THEN ; : COUNTDOWN DUP IF DUP 1- RECURSE THEN ; : COUNTDOWN DUP IF DUP 1- RECURSE THEN ; 3 COUNTDOWN",
+        GasLimit::Limited(500),
+    )
+    .unwrap();
+
+    assert_eq!(
+        fc.word_addresses.get("COUNTDOWN"),
+        Some(&FIRST_WORD_ADDRESS)
+    );
+    assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 2, 1, 0]);
+}
+
+#[test]
+fn test_recurse_out_of_gas() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(": FOREVER RECURSE ; FOREVER", GasLimit::Limited(1000)) {
+        Err(ForthError::RanOutOfGas { .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_recurse_outside_word_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("RECURSE", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_if_else_uneven_run() {
+    let mut fc = ForthCompiler::default();
+
+    // The IF and ELSE parts being different lengths mustn't change where the jumps go
+    fc.execute_string(
+        "0 IF 1 ELSE 2 3 ADD THEN 1 IF 1 ELSE 2 3 ADD THEN 0 IF 1 2 3 ADD ADD ELSE 7 THEN",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 1, 7]);
+}
+
+#[test]
+fn test_defer_mutual_recursion_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        "DEFER ODD? : EVEN? DUP 0= IF DROP -1 ELSE 1- ODD? THEN ; : ODD-IMPL DUP 0= IF DROP 0 ELSE 1- EVEN? THEN ; ' ODD-IMPL IS ODD? 10 EVEN? 7 EVEN? 7 ODD?",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64, 0, -1]);
+}
+
+#[test]
+fn test_defer_is_changed_run() {
+    let mut fc = ForthCompiler::default();
+
+    // IS works inside a definition too
+    fc.execute_string(
+        "DEFER OP : APPLY OP ; : PLUS ADD ; : TIMES MUL ; : SET-TIMES ' TIMES IS OP ; ' PLUS IS OP 3 4 APPLY SET-TIMES 3 4 APPLY",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![7_i64, 12]);
+}
+
+#[test]
+fn test_defer_unset_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("DEFER NOTHING-YET NOTHING-YET", GasLimit::Limited(100)) {
+        Err(ForthError::UndefinedWord(word_name)) => assert_eq!(word_name, "NOTHING-YET"),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_is_without_defer_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(": A ; : B ; ' A IS B", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_tick_intrinsic_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("DEFER D ' DUP IS D", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_defer_target_kept_after_redefinition_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("DEFER D : FIVE 5 ; ' FIVE IS D", GasLimit::Limited(100))
+        .unwrap();
+    // Nothing calls the first FIVE by name any more, but D still runs it
+    fc.execute_string(
+        ": FIVE 6 ; : SEVEN 7 ; D FIVE SEVEN",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 6, 7]);
+}
+
+#[test]
+fn test_forward_mutual_recursion_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        "FORWARD ODD? : EVEN? DUP 0= IF DROP -1 ELSE 1- ODD? THEN ; : ODD? DUP 0= IF DROP 0 ELSE 1- EVEN? THEN ; 10 EVEN? 7 EVEN? 7 ODD?",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64, 0, -1]);
+}
+
+#[test]
+fn test_forward_call_sites_patched() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        "FORWARD LATER : EARLY LATER 1 ; : LATER 2 ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    // EARLY calls LATER directly, and the FORWARD code has been given back
+    let later = *fc.word_addresses.get("LATER").unwrap();
+    let early = *fc.word_addresses.get("EARLY").unwrap();
+    assert_eq!(
+        &fc.sm.st.opcodes[early..early + 2],
+        &[Opcode::LDI(later as i64), Opcode::CALL]
+    );
+    assert_eq!(fc.dictionary.len(), 2);
+
+    fc.execute_string("EARLY", GasLimit::Limited(100)).unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![2_i64, 1]);
+}
+
+#[test]
+fn test_forward_undefined_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(
+        "FORWARD LATER : EARLY LATER ; EARLY",
+        GasLimit::Limited(100),
+    ) {
+        Err(ForthError::UndefinedWord(word_name)) => assert_eq!(word_name, "LATER"),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_tick_execute_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": FIVE 5 ; ' FIVE DUP EXECUTE SWAP EXECUTE",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 5]);
+}
+
+#[test]
+fn test_tick_address_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": FIVE 5 ; ' FIVE", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(
+        &fc.sm.st.number_stack,
+        &vec![*fc.word_addresses.get("FIVE").unwrap() as i64]
+    );
+}
+
+#[test]
+fn test_bracket_tick_dispatch_table_run() {
+    let mut fc = ForthCompiler::default();
+
+    // A table of operations kept in variables, picked by number
+    fc.execute_string(
+        ": PLUS ADD ; : TIMES MUL ; VARIABLE OPS VARIABLE OPS1 : INIT ['] PLUS OPS ! ['] TIMES OPS1 ! ; : DISPATCH OPS ADD @ EXECUTE ; INIT 3 4 0 DISPATCH 3 4 1 DISPATCH",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![7_i64, 12]);
+}
+
+#[test]
+fn test_execute_callback_run() {
+    let mut fc = ForthCompiler::default();
+
+    // A word taking another word to run on each loop index
+    fc.execute_string(
+        ": SQUARE DUP MUL ; : EACH 4 0 DO I OVER EXECUTE SWAP LOOP DROP ; ' SQUARE EACH",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 4, 9]);
+}
+
+#[test]
+fn test_tick_unknown_word_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("' NOWHERE EXECUTE", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_bracket_tick_without_name_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(": BROKEN ['] ;", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_case() {
+    let tokenizer = ForthTokenizer::new("CASE 1 OF 10 ENDOF 20 ENDCASE");
+    let mut fc = ForthCompiler::default();
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI(1),
+            Opcode::GtR,
+            Opcode::DUP,
+            Opcode::RGt,
+            Opcode::SWAP,
+            Opcode::SUB,
+            Opcode::CMPZ,
+            Opcode::LDI(5),
+            Opcode::JRZ,
+            Opcode::DROP,
+            Opcode::LDI(10),
+            Opcode::LDI(3),
+            Opcode::JR,
+            Opcode::LDI(20),
+            Opcode::DROP,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_case_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": DECODE CASE 1 OF 100 ENDOF 2 OF 200 ENDOF 3 OF 300 ENDOF -1 SWAP ENDCASE ; 1 DECODE 2 DECODE 3 DECODE 4 DECODE",
+        GasLimit::Limited(500),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![100_i64, 200, 300, -1]);
+}
+
+#[test]
+fn test_case_default_sees_value_run() {
+    let mut fc = ForthCompiler::default();
+
+    // With no match, the value is still on the stack for the default code, ENDCASE drops it
+    fc.execute_string(
+        "7 CASE 1 OF 100 ENDOF DUP 2 MUL SWAP ENDCASE",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![14_i64]);
+}
+
+#[test]
+fn test_case_nested_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": CLASSIFY CASE 1 OF CASE 1 OF 11 ENDOF 2 OF 12 ENDOF 0 SWAP ENDCASE ENDOF 2 OF DROP 20 ENDOF 2DROP -1 0 ENDCASE ; 1 1 CLASSIFY 2 1 CLASSIFY 5 2 CLASSIFY 5 5 CLASSIFY",
+        GasLimit::Limited(500),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![11_i64, 12, 20, -1]);
+}
+
+#[test]
+fn test_case_in_loop_run() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        "4 0 DO I CASE 0 OF 10 ENDOF 2 OF 12 ENDOF ENDCASE LOOP",
+        GasLimit::Limited(500),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![10_i64, 12]);
+}
+
+#[test]
+fn test_of_without_case_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(": BROKEN 1 OF 2 ENDOF ;", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_endcase_without_endof_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(": BROKEN CASE 1 OF 2 ENDCASE ;", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_unterminated_if_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(": BROKEN DUP IF DROP ;", GasLimit::Limited(100)) {
+        Err(ForthError::UnterminatedControlStructure { word, position }) => {
+            assert_eq!(word, "IF");
+            assert_eq!(position, 3);
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_unterminated_do_fail() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("1 2 3 10 0 DO I", GasLimit::Limited(100)) {
+        Err(ForthError::UnterminatedControlStructure { word, position }) => {
+            assert_eq!(word, "DO");
+            assert_eq!(position, 5);
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_unterminated_innermost_fail() {
+    let mut fc = ForthCompiler::default();
+
+    // The innermost structure left open is the one reported
+    match fc.execute_string(
+        ": BROKEN BEGIN 5 0 ?DO I LOOP CASE 1 OF 2 ENDOF ;",
+        GasLimit::Limited(100),
+    ) {
+        Err(ForthError::UnterminatedControlStructure { word, position }) => {
+            assert_eq!(word, "CASE");
+            assert_eq!(position, 8);
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    match fc.execute_string(": BROKEN BEGIN 5 0 ?DO I LOOP ;", GasLimit::Limited(100)) {
+        Err(ForthError::UnterminatedControlStructure { word, position }) => {
+            assert_eq!(word, "BEGIN");
+            assert_eq!(position, 2);
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_unterminated_question_do_exit_code() {
+    let mut fc = ForthCompiler::default();
+
+    let err = fc
+        .execute_string("( count ) 3 0 ?DO", GasLimit::Limited(100))
+        .unwrap_err();
+
+    assert_eq!(err.to_string(), "?DO at token 3 is never closed");
+    assert_eq!(i32::from(err), 17);
+}
+
+#[test]
+fn test_unterminated_word_not_defined() {
+    let mut fc = ForthCompiler::default();
+
+    let _ = fc.execute_string(": BROKEN 1 IF 2 ;", GasLimit::Limited(100));
+
+    assert!(!fc.word_addresses.contains_key("BROKEN"));
+}
+
+#[test]
+fn test_compile_error_location() {
+    let mut fc = ForthCompiler::default();
+
+    let err = fc
+        .execute_string(
+            ": SQUARE DUP MUL ;\n: CUBE DUP SQUARE\n  MULT ;\n2 CUBE",
+            GasLimit::Limited(100),
+        )
+        .unwrap_err();
+
+    match err {
+        ForthError::UnknownToken(ref token) => assert_eq!(token, "MULT"),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(
+        fc.last_error_location(),
+        Some(&SourceLocation {
+            token: 9,
+            line: 3,
+            column: 3,
+            word: Some("CUBE".to_owned()),
+        })
+    );
+    assert_eq!(
+        fc.last_error_location().unwrap().to_string(),
+        "line 3, column 3, in the definition of CUBE"
+    );
+    assert_eq!(i32::from(err), 3);
+
+    // The next run that compiles forgets it
+    fc.execute_string("1", GasLimit::Limited(100)).unwrap();
+    assert_eq!(fc.last_error_location(), None);
+}
+
+#[test]
+fn test_compile_error_location_interactive() {
+    let mut fc = ForthCompiler::default();
+
+    // Comments, strings and numbers all count when finding the column
+    fc.execute_string(
+        "\\ a comment\n( remark ) .\" hi\" 12 -3 ; 4",
+        GasLimit::Limited(100),
+    )
+    .unwrap_err();
+
+    let location = fc.last_error_location().unwrap();
+    assert_eq!((location.token, location.line, location.column), (5, 2, 25));
+    assert_eq!(location.word, None);
+    assert_eq!(
+        location.caret("\\ a comment\n( remark ) .\" hi\" 12 -3 ; 4"),
+        Some("( remark ) .\" hi\" 12 -3 ; 4\n                        ^".to_owned())
+    );
+}
+
+#[test]
+fn test_compile_error_location_unterminated() {
+    let mut fc = ForthCompiler::default();
+
+    // Points at the IF that was never closed, not the end of the definition
+    fc.execute_string(": CHECK\n  DUP 0< IF\n  NEGATE ;", GasLimit::Limited(100))
+        .unwrap_err();
+
+    let location = fc.last_error_location().unwrap();
+    assert_eq!((location.line, location.column), (2, 10));
+    assert_eq!(location.word.as_deref(), Some("CHECK"));
+}
+
+#[test]
+fn test_compile_error_location_without_source() {
+    let tokenizer = ForthTokenizer::new("1 2 FROB");
+    let mut fc = ForthCompiler::default();
+
+    // Without the source text only the token position is known
+    fc.compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap_err();
+
+    assert_eq!(fc.last_error_location().unwrap().to_string(), "token 2");
+}
+
+#[test]
+fn test_runtime_error_has_no_location() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string("1 FROB", GasLimit::Limited(100))
+        .unwrap_err();
+    fc.execute_string("DROP", GasLimit::Limited(100))
+        .unwrap_err();
+
+    assert!(fc.last_error_location().is_none());
+}
+
+#[test]
+fn test_backtrace_nested_words() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    match fc.execute_string(
+        ": INNER DROP DROP ; : MIDDLE 1 INNER ; : OUTER MIDDLE ; OUTER",
+        GasLimit::Limited(100),
+    ) {
+        Err(ForthError::NumberStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    let backtrace = fc.last_backtrace().unwrap();
+    let frames: Vec<(Option<&str>, Option<&str>)> = backtrace
+        .frames
+        .iter()
+        .map(|frame| (frame.word.as_deref(), frame.token.as_deref()))
+        .collect();
+    assert_eq!(
+        frames,
+        vec![
+            (Some("INNER"), Some("DROP")),
+            (Some("MIDDLE"), Some("INNER")),
+            (Some("OUTER"), Some("MIDDLE")),
+            (None, Some("OUTER")),
+        ]
+    );
+    // The second DROP is the one that failed
+    let inner = *fc.word_addresses.get("INNER").unwrap();
+    assert_eq!(backtrace.frames[0].address, inner + 1);
+}
+
+#[test]
+fn test_backtrace_syscall() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    match fc.execute_string(": PEEK 5 PICK ; 1 PEEK", GasLimit::Limited(100)) {
+        Err(ForthError::NumberStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    assert_eq!(
+        fc.last_backtrace().unwrap().to_string(),
+        format!(
+            "  in PEEK at PICK (address {})\n  in interactive code at PEEK (address {})\n",
+            FIRST_WORD_ADDRESS + 3,
+            fc.last_function + 2
+        )
+    );
+}
+
+#[test]
+fn test_backtrace_out_of_gas() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    match fc.execute_string(
+        ": SPIN BEGIN 0 UNTIL ; : START SPIN ; START",
+        GasLimit::Limited(100),
+    ) {
+        Err(ForthError::RanOutOfGas { .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    let frames = &fc.last_backtrace().unwrap().frames;
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[0].word.as_deref(), Some("SPIN"));
+    assert_eq!(frames[1].word.as_deref(), Some("START"));
+    assert_eq!(frames[2].word, None);
+}
+
+#[test]
+fn test_backtrace_cleared_by_success() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    assert!(fc.execute_string("DROP", GasLimit::Limited(100)).is_err());
+    assert!(fc.last_backtrace().is_some());
+
+    fc.execute_string("1", GasLimit::Limited(100)).unwrap();
+    assert!(fc.last_backtrace().is_none());
+}
+
+#[test]
+fn test_backtraces_off_by_default() {
+    let mut fc = ForthCompiler::default();
+
+    // The StackMachine runs the whole program in one go
+    fc.execute_string("1 2 ADD 3 MUL", GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(fc.sm.st.gas_used(), fc.gas_used());
+    assert!(fc.gas_used() > 1);
+
+    match fc.execute_string("1 0 DIV", GasLimit::Limited(100)) {
+        Err(ForthError::DivisionByZero { address: None, .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert!(fc.last_backtrace().is_none());
+}
+
+#[test]
+fn test_backtrace_in_freed_memory() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    // Redefining A frees the first one, running what was left there has no word to blame
+    fc.execute_string(": A DROP ; : A 1 ;", GasLimit::Limited(100))
+        .unwrap();
+    let freed = FIRST_WORD_ADDRESS;
+    assert!(fc.last_function > freed);
+    fc.execute_string(&format!("{} EXECUTE", freed), GasLimit::Limited(100))
+        .unwrap_err();
+
+    let frames = &fc.last_backtrace().unwrap().frames;
+    assert_eq!(frames[0].word, None);
+    assert_eq!(frames[0].token, None);
+}
+
+#[test]
+fn test_runtime_error_resets_control_stacks() {
+    let mut fc = ForthCompiler::default();
+
+    // Failing inside a word and inside a loop mustn't leave anything behind for the next run
+    assert!(
+        fc.execute_string(": BAD 3 0 DO DROP LOOP ; BAD", GasLimit::Limited(100))
+            .is_err()
+    );
+    fc.execute_string("5 6", GasLimit::Limited(100)).unwrap();
+    assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 6]);
+
+    match fc.execute_string("I", GasLimit::Limited(100)) {
+        Err(ForthError::LoopStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_runtime_error_resets_scratch_stack() {
+    let mut fc = ForthCompiler::default();
+
+    // OVER parks the top of the stack on the scratch stack before it finds there is nothing under it
+    match fc.execute_string("1 OVER", GasLimit::Limited(100)) {
+        Err(ForthError::NumberStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    assert_eq!(&fc.sm.st.scratch_stack, &Vec::<i64>::new());
+}
+
+#[test]
+fn test_division_by_zero_in_word_address() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    let err = fc
+        .execute_string(
+            ": HALF 0 DIV ; : AVG ADD HALF ; 1 2 AVG",
+            GasLimit::Limited(100),
+        )
+        .unwrap_err();
+
+    let half = *fc.word_addresses.get("HALF").unwrap();
+    assert_eq!(
+        err.to_string(),
+        format!("Division by zero in DIV at address {}", half + 1)
+    );
+    assert_eq!(i32::from(err), 18);
+}
+
+#[test]
+fn test_numeric_overflow_exit_code() {
+    let mut fc = ForthCompiler::default();
+
+    let err = fc
+        .execute_string("9223372036854775807 1 ADD", GasLimit::Limited(100))
+        .unwrap_err();
+
+    assert_eq!(i32::from(err), 19);
+}
+
+#[test]
+fn test_stack_machine_error_without_address() {
+    let err = ForthError::from(StackMachineError::DivisionByZero {
+        failing_opcode: Opcode::DIV,
+    });
+
+    assert_eq!(err.to_string(), "Division by zero in DIV");
+}
+
+// Scripts depend on these, so they must never change
+#[test]
+fn test_exit_codes_stable() {
+    let codes: Vec<(ForthError, i32)> = vec![
+        (ForthError::UnknownError, 2),
+        (ForthError::UnknownToken("X".to_owned()), 3),
+        (ForthError::NumberStackUnderflow, 4),
+        (ForthError::LoopStackUnderflow, 5),
+        (ForthError::InvalidSyntax("X".to_owned()), 6),
+        (ForthError::MissingSemicolonAfterColon, 7),
+        (ForthError::MissingCommandAfterColon, 8),
+        (ForthError::SemicolonBeforeColon, 9),
+        (
+            ForthError::UnhandledTrap {
+                unhandled_trap_id: 1,
+            },
+            10,
+        ),
+        (
+            ForthError::RanOutOfGas {
+                gas_used: 1,
+                gas_limit: GasLimit::Limited(0),
+            },
+            11,
+        ),
+        (ForthError::InternalNumericOverflow, 12),
+        (ForthError::ScratchStackUnderflow, 13),
+        (ForthError::InvalidCellOperation, 14),
+        (ForthError::OutputError(std::io::Error::other("X")), 15),
+        (ForthError::UndefinedWord("X".to_owned()), 16),
+        (
+            ForthError::UnterminatedControlStructure {
+                word: "IF".to_owned(),
+                position: 0,
+            },
+            17,
+        ),
+        (
+            ForthError::DivisionByZero {
+                opcode: Opcode::DIV,
+                address: Some(2),
+            },
+            18,
+        ),
+        (
+            ForthError::NumericOverflow {
+                opcode: Opcode::ADD,
+                address: None,
+            },
+            19,
+        ),
+        (ForthError::InvalidImage("X".to_owned()), 20),
+        (
+            ForthError::StackEffectMismatch {
+                word: "X".to_owned(),
+                declared: StackEffect {
+                    inputs: 1,
+                    outputs: 1,
+                },
+                actual: None,
+            },
+            21,
+        ),
+    ];
+
+    for (err, code) in codes {
+        let description = format!("{:?}", err);
+        assert_eq!(i32::from(err), code, "{}", description);
+    }
+}
+
+#[test]
+fn test_image_round_trip() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(
+        "VARIABLE total 10 CONSTANT ten 3 VALUE level DEFER STEP : DOUBLE 2 MUL ; ' DOUBLE IS STEP 35 total ! : RESULT total @ ten ADD level STEP ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+
+    let mut loaded = ForthCompiler::default();
+    loaded.load_image(image.as_slice()).unwrap();
+    loaded
+        .execute_string("RESULT", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(&loaded.sm.st.number_stack, &vec![45_i64, 6]);
+}
+
+#[test]
+fn test_image_keeps_compiling() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(
+        "MARKER -extras VARIABLE x : SQUARE DUP MUL ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+
+    let mut loaded = ForthCompiler::default();
+    loaded.load_image(image.as_slice()).unwrap();
+    loaded
+        .execute_string(
+            "VARIABLE y : CUBE DUP SQUARE MUL ; 3 CUBE y ! 4 SQUARE x ! x @ y @",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+    assert_eq!(&loaded.sm.st.number_stack, &vec![16_i64, 27]);
+
+    loaded
+        .execute_string("-extras", GasLimit::Limited(100))
+        .unwrap();
+    match loaded.execute_string("SQUARE", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_image_does_not_save_the_stack() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string("VARIABLE x 9 x ! 1 2 3", GasLimit::Limited(100))
+        .unwrap();
+
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2, 3]);
+
+    let mut loaded = ForthCompiler::default();
+    loaded.load_image(image.as_slice()).unwrap();
+    assert_eq!(&loaded.sm.st.number_stack, &Vec::<i64>::new());
+}
+
+#[test]
+fn test_image_bad_magic() {
+    let mut fc = ForthCompiler::default();
+    match fc.load_image(b"NOPE\x01\x00\x00\x00".as_slice()) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_image_unsupported_version() {
+    let mut fc = ForthCompiler::default();
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+    image[4] = 99;
+
+    match fc.load_image(image.as_slice()) {
+        Err(ForthError::InvalidImage(reason)) => assert!(reason.contains("99"), "{}", reason),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_image_truncated() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(": SQUARE DUP MUL ;", GasLimit::Limited(100))
+        .unwrap();
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+
+    let mut loaded = ForthCompiler::default();
+    match loaded.load_image(&image[..image.len() - 3]) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    // A failed load leaves the compiler as it was
+    loaded
+        .execute_string("2 DUP MUL", GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(&loaded.sm.st.number_stack, &vec![4_i64]);
+}
+
+// Saves fc as it is, however broken, and loads it into a new compiler
+fn reload_image(fc: &mut ForthCompiler) -> Result<ForthCompiler, ForthError> {
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+    let mut loaded = ForthCompiler::default();
+    loaded.load_image(image.as_slice())?;
+    Ok(loaded)
+}
+
+#[test]
+fn test_image_missing_syscall_entry() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(": SQUARE DUP MUL ;", GasLimit::Limited(100))
+        .unwrap();
+    fc.sm.st.opcodes[0] = Opcode::NOP;
+
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_image_ends_before_first_word() {
+    let mut fc = ForthCompiler {
+        last_function: FIRST_WORD_ADDRESS - 1,
+        ..Default::default()
+    };
+
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_image_word_before_first_address() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(": SQUARE DUP MUL ;", GasLimit::Limited(100))
+        .unwrap();
+    fc.dictionary[0].start = SYSCALL_ENTRY;
+    fc.word_addresses.insert("SQUARE".to_owned(), SYSCALL_ENTRY);
+
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_image_overlapping_words() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(": A 1 ; : B 2 ;", GasLimit::Limited(100))
+        .unwrap();
+    let start = fc.dictionary[0].start;
+    fc.dictionary[1].start = start;
+    fc.word_addresses.insert("B".to_owned(), start);
+
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(reason)) => assert!(reason.contains("overlap"), "{}", reason),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_image_name_without_word() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(": A 1 ; : B 2 ;", GasLimit::Limited(100))
+        .unwrap();
+    fc.word_addresses
+        .insert("B".to_owned(), fc.dictionary[0].start);
+
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_image_cell_outside_cells() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string("5 VALUE FIVE DEFER LATER", GasLimit::Limited(100))
+        .unwrap();
+    fc.value_addresses.insert("FIVE".to_owned(), 2);
+
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+
+    fc.value_addresses.insert("FIVE".to_owned(), 0);
+    fc.deferred_slots.insert("LATER".to_owned(), 7);
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+
+    // A cell reserved by a run that failed is allocated by the next one
+    fc.deferred_slots.insert("LATER".to_owned(), 1);
+    fc.execute_string("VARIABLE V FROB", GasLimit::Limited(100))
+        .unwrap_err();
+    let mut loaded = reload_image(&mut fc).unwrap();
+    loaded
+        .execute_string("7 V ! V @ FIVE", GasLimit::Limited(100))
+        .unwrap();
+    assert_eq!(&loaded.sm.st.number_stack, &vec![7_i64, 5]);
+}
+
+#[test]
+fn test_image_too_many_cells_reserved() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string("VARIABLE V", GasLimit::Limited(100))
+        .unwrap();
+    fc.cells_reserved = 1 << 40;
+
+    match reload_image(&mut fc) {
+        Err(ForthError::InvalidImage(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_optimize_off_by_default() {
+    let tokenizer = ForthTokenizer::new("2 3 ADD");
+    let mut fc = ForthCompiler::default();
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    assert_eq!(
+        &ol,
+        &vec![Opcode::LDI(2), Opcode::LDI(3), Opcode::ADD, Opcode::RET]
+    );
+}
+
+#[test]
+fn test_optimize_folds_constants() {
+    let tokenizer = ForthTokenizer::new("2 3 ADD 1+ 4 MUL 7 SUB DUP 1 2 DROP DROP 0=");
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    // SUB leaves 7 - 24
+    assert_eq!(
+        &ol,
+        &vec![Opcode::LDI(-17), Opcode::DUP, Opcode::CMPZ, Opcode::RET]
+    );
+}
+
+#[test]
+fn test_optimize_leaves_errors_to_runtime() {
+    let tokenizer = ForthTokenizer::new("1 0 DIV 9223372036854775807 1 ADD");
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI(1),
+            Opcode::LDI(0),
+            Opcode::DIV,
+            Opcode::LDI(i64::MAX),
+            Opcode::LDI(1),
+            Opcode::ADD,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_empty_if() {
+    let tokenizer = ForthTokenizer::new("DUP IF THEN DUP IF 1 ELSE THEN");
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    // The IF jump over the 1 is pulled back now the ELSE jump is gone
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::DUP,
+            Opcode::DROP,
+            Opcode::DUP,
+            Opcode::LDI(2),
+            Opcode::JRZ,
+            Opcode::LDI(1),
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_keeps_jump_targets() {
+    let tokenizer = ForthTokenizer::new("IF 1 ELSE 2 THEN 3 ADD");
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+    let ol = fc
+        .compile_tokens_compile_and_remove_word_definitions(&tokenizer)
+        .unwrap();
+    // Both branches land on the 3, so 2 3 ADD can't be folded
+    assert_eq!(
+        &ol,
+        &vec![
+            Opcode::LDI(4),
+            Opcode::JRZ,
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::JR,
+            Opcode::LDI(2),
+            Opcode::LDI(3),
+            Opcode::ADD,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_recurse() {
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+
+    fc.execute_string(
+        ": COUNTDOWN 0 1 ADD DROP DUP IF 1- RECURSE THEN ; 5 COUNTDOWN",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_optimize_same_results() {
+    let programs = [
+        ": F IF 1 ELSE 2 THEN 3 ADD ; 0 F 1 F",
+        ": SUM 0 10 0 DO I 2 3 MUL MUL ADD LOOP ; SUM",
+        ": ODDS 0 10 0 DO I 1 AND IF I ADD THEN 2 +LOOP ; ODDS",
+        ": FIND 0 BEGIN 1+ DUP 7 = UNTIL ; FIND",
+        ": F 0 BEGIN DUP 5 < WHILE 1+ REPEAT ; F",
+        ": F 10 0 DO I 3 = IF I LEAVE THEN LOOP ; F",
+        ": F 0 0 ?DO 1 LOOP 3 1 ?DO I LOOP ; F",
+        ": F CASE 1 OF 10 ENDOF 2 OF 1 1 ADD ENDOF 99 SWAP ENDCASE ; 1 F 2 F 3 F",
+        ": F 5 0 DO I 2 = IF I UNLOOP EXIT THEN LOOP 0 ; F",
+        ": FIB DUP 2 < IF EXIT THEN DUP 1- RECURSE SWAP 2- RECURSE ADD ; 10 FIB",
+        "DEFER D : SQ DUP MUL ; ' SQ IS D 2 3 ADD D",
+        "VARIABLE V 6 7 MUL V ! V @ 0 NOT 5 0= 5 0 <>",
+    ];
+
+    for program in programs {
+        let mut plain = ForthCompiler::default();
+        plain
+            .execute_string(program, GasLimit::Limited(10000))
+            .unwrap();
+
+        let mut optimized = ForthCompiler::default();
+        optimized.set_optimize(true);
+        optimized
+            .execute_string(program, GasLimit::Limited(10000))
+            .unwrap();
+
+        assert_eq!(
+            &optimized.sm.st.number_stack, &plain.sm.st.number_stack,
+            "{}",
+            program
+        );
+        assert!(
+            optimized.gas_used() <= plain.gas_used(),
+            "{} used {} gas optimized, {} without",
+            program,
+            optimized.gas_used(),
+            plain.gas_used()
+        );
+    }
+}
+
+#[test]
+fn test_optimize_gas_savings() {
+    let program = ": SUM 0 100 0 DO 2 3 MUL ADD LOOP ; SUM";
+
+    let mut plain = ForthCompiler::default();
+    plain
+        .execute_string(program, GasLimit::Limited(10000))
+        .unwrap();
+
+    let mut optimized = ForthCompiler::default();
+    optimized.set_optimize(true);
+    optimized
+        .execute_string(program, GasLimit::Limited(10000))
+        .unwrap();
+
+    assert_eq!(&plain.sm.st.number_stack, &vec![600_i64]);
+    assert_eq!(&optimized.sm.st.number_stack, &vec![600_i64]);
+    // 2 3 MUL is one LDI instead of three opcodes, each time round the loop
+    assert_eq!(plain.gas_used() - optimized.gas_used(), 200);
+}
+
+#[test]
+fn test_optimize_gas_savings_empty_branches() {
+    let program = ": F 100 0 DO I IF ELSE THEN LOOP ; F";
+
+    let mut plain = ForthCompiler::default();
+    plain
+        .execute_string(program, GasLimit::Limited(10000))
+        .unwrap();
+
+    let mut optimized = ForthCompiler::default();
+    optimized.set_optimize(true);
+    optimized
+        .execute_string(program, GasLimit::Limited(10000))
+        .unwrap();
+
+    assert!(
+        optimized.gas_used() < plain.gas_used(),
+        "{} used, {} without optimizing",
+        optimized.gas_used(),
+        plain.gas_used()
+    );
+}
+
+#[test]
+fn test_optimize_backtrace() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+    fc.set_optimize(true);
+
+    match fc.execute_string(": F 1 2 ADD 0 DIV ; F", GasLimit::Limited(100)) {
+        Err(ForthError::DivisionByZero { .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    let frame = &fc.last_backtrace().unwrap().frames[0];
+    assert_eq!(frame.word.as_deref(), Some("F"));
+    assert_eq!(frame.token.as_deref(), Some("DIV"));
+    assert_eq!(frame.address, FIRST_WORD_ADDRESS + 2);
+}
+
+#[test]
+fn test_inline_marked_word() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": LOVE 2 ; INLINE : TWICE LOVE LOVE ADD ; TWICE",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    let start = fc.word_addresses["TWICE"];
+    assert_eq!(
+        &fc.sm.st.opcodes[start..start + 4],
+        &[Opcode::LDI(2), Opcode::LDI(2), Opcode::ADD, Opcode::RET]
+    );
+    assert_eq!(&fc.sm.st.number_stack, &vec![4_i64]);
+}
+
+#[test]
+fn test_inline_off_by_default() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": LOVE 2 ; : TWICE LOVE LOVE ADD ;", GasLimit::Limited(100))
+        .unwrap();
+
+    let love = fc.word_addresses["LOVE"] as i64;
+    let start = fc.word_addresses["TWICE"];
+    assert_eq!(
+        &fc.sm.st.opcodes[start..start + 6],
+        &[
+            Opcode::LDI(love),
+            Opcode::CALL,
+            Opcode::LDI(love),
+            Opcode::CALL,
+            Opcode::ADD,
+            Opcode::RET
+        ]
+    );
+}
+
+#[test]
+fn test_inline_threshold() {
+    let mut fc = ForthCompiler::default();
+    fc.set_inline_threshold(2);
+
+    fc.execute_string(
+        ": SQUARE DUP MUL ; : CUBE DUP SQUARE MUL ; : BIG DUP CUBE SQUARE ; 3 CUBE 2 BIG",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    // SQUARE fits, CUBE is 3 opcodes once SQUARE is inlined into it, so BIG has to call it
+    let cube = fc.word_addresses["CUBE"];
+    assert_eq!(
+        &fc.sm.st.opcodes[cube..cube + 5],
+        &[
+            Opcode::DUP,
+            Opcode::DUP,
+            Opcode::MUL,
+            Opcode::MUL,
+            Opcode::RET
+        ]
+    );
+    let big = fc.word_addresses["BIG"];
+    assert_eq!(
+        &fc.sm.st.opcodes[big..big + 6],
+        &[
+            Opcode::DUP,
+            Opcode::LDI(cube as i64),
+            Opcode::CALL,
+            Opcode::DUP,
+            Opcode::MUL,
+            Opcode::RET
+        ]
+    );
+    assert_eq!(&fc.sm.st.number_stack, &vec![27_i64, 2, 64]);
+}
+
+#[test]
+fn test_inline_jumps() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": ABS2 DUP 0< IF NEGATE THEN ; INLINE : DIST SUB ABS2 1+ ; 3 8 DIST 8 3 DIST",
+        GasLimit::Limited(200),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![6_i64, 6]);
+}
+
+#[test]
+fn test_inline_loops() {
+    let mut fc = ForthCompiler::default();
+    fc.set_inline_threshold(20);
+
+    fc.execute_string(
+        ": SUM 0 SWAP 0 DO I ADD LOOP ; : F 4 SUM 5 SUM ; F",
+        GasLimit::Limited(200),
+    )
+    .unwrap();
+
+    let f = &fc.dictionary[fc.current_entry("F").unwrap()];
+    assert!(!fc.sm.st.opcodes[f.start..f.end()].contains(&Opcode::CALL));
+    assert_eq!(&fc.sm.st.number_stack, &vec![6_i64, 10]);
+}
+
+#[test]
+fn test_inline_exit_is_called() {
+    let mut fc = ForthCompiler::default();
+    fc.set_inline_threshold(20);
+
+    fc.execute_string(
+        ": CLAMP DUP 10 > IF DROP 10 EXIT THEN ; : F CLAMP 1+ ; 5 F 50 F",
+        GasLimit::Limited(200),
+    )
+    .unwrap();
+
+    let clamp = fc.word_addresses["CLAMP"] as i64;
+    let start = fc.word_addresses["F"];
+    assert_eq!(
+        &fc.sm.st.opcodes[start..start + 2],
+        &[Opcode::LDI(clamp), Opcode::CALL]
+    );
+    assert_eq!(&fc.sm.st.number_stack, &vec![6_i64, 11]);
+}
+
+#[test]
+fn test_inline_exit_marked() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string(
+        ": CLAMP DUP 10 > IF DROP 10 EXIT THEN ; INLINE",
+        GasLimit::Limited(100),
+    ) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_inline_without_word() {
+    let mut fc = ForthCompiler::default();
+
+    match fc.execute_string("INLINE", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    match fc.execute_string("DEFER D INLINE", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_inline_redefined() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": LOVE 2 ; INLINE : TWICE LOVE LOVE ADD ; : LOVE 3 ; TWICE LOVE",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    // Like a call, TWICE keeps the LOVE it was compiled with
+    assert_eq!(&fc.sm.st.number_stack, &vec![4_i64, 3]);
+}
+
+#[test]
+fn test_inline_image() {
+    let mut fc = ForthCompiler::default();
+    fc.execute_string(": LOVE 2 ; INLINE", GasLimit::Limited(100))
+        .unwrap();
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+
+    let mut loaded = ForthCompiler::default();
+    loaded.load_image(image.as_slice()).unwrap();
+    loaded
+        .execute_string(": TWICE LOVE LOVE ADD ;", GasLimit::Limited(100))
+        .unwrap();
+
+    let start = loaded.word_addresses["TWICE"];
+    assert_eq!(
+        &loaded.sm.st.opcodes[start..start + 4],
+        &[Opcode::LDI(2), Opcode::LDI(2), Opcode::ADD, Opcode::RET]
+    );
+}
+
+#[test]
+fn test_inline_backtrace() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+
+    match fc.execute_string(
+        ": INNER DROP DROP ; INLINE : OUTER 1 INNER ; OUTER",
+        GasLimit::Limited(100),
+    ) {
+        Err(ForthError::NumberStackUnderflow) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    // The inlined code belongs to OUTER now, at the token that called INNER
+    assert_eq!(
+        fc.last_backtrace().unwrap().to_string(),
+        format!(
+            "  in OUTER at INNER (address {})\n  in interactive code at OUTER (address {})\n",
+            fc.word_addresses["OUTER"] + 2,
+            fc.last_function + 1
+        )
+    );
+}
+
+#[test]
+fn test_inline_gas_savings() {
+    let program = ": INC 1+ ; : INC2 INC INC ; : INC4 INC2 INC2 ; : F 0 50 0 DO INC4 LOOP ; F";
+
+    let mut plain = ForthCompiler::default();
+    plain
+        .execute_string(program, GasLimit::Limited(10000))
+        .unwrap();
+
+    let mut inlined = ForthCompiler::default();
+    inlined.set_inline_threshold(8);
+    inlined
+        .execute_string(program, GasLimit::Limited(10000))
+        .unwrap();
+
+    let mut optimized = ForthCompiler::default();
+    optimized.set_inline_threshold(8);
+    optimized.set_optimize(true);
+    optimized
+        .execute_string(program, GasLimit::Limited(10000))
+        .unwrap();
+
+    assert_eq!(&plain.sm.st.number_stack, &vec![200_i64]);
+    assert_eq!(&inlined.sm.st.number_stack, &vec![200_i64]);
+    assert_eq!(&optimized.sm.st.number_stack, &vec![200_i64]);
+    // Each INC4 was 7 calls, each an LDI, CALL and RET
+    assert_eq!(plain.gas_used() - inlined.gas_used(), 50 * 7 * 3);
+    assert!(optimized.gas_used() <= inlined.gas_used());
+}
+
+#[test]
+fn test_tail_call() {
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+
+    fc.execute_string(
+        ": A 1 ; : B A ; : C A 2 ; : D DUP IF A EXIT THEN 3 ; : E EXECUTE ; : F . ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    let a = fc.word_addresses["A"] as i64;
+    let code = |fc: &ForthCompiler, name: &str| {
+        let entry = &fc.dictionary[fc.current_entry(name).unwrap()];
+        fc.sm.st.opcodes[entry.start..entry.end()].to_vec()
+    };
+    assert_eq!(
+        code(&fc, "B"),
+        vec![Opcode::LDI(a), Opcode::JMP, Opcode::RET]
+    );
+    assert_eq!(
+        code(&fc, "C"),
+        vec![Opcode::LDI(a), Opcode::CALL, Opcode::LDI(2), Opcode::RET]
+    );
+    // The call before EXIT is a tail call too
+    assert_eq!(
+        code(&fc, "D"),
+        vec![
+            Opcode::DUP,
+            Opcode::LDI(4),
+            Opcode::JRZ,
+            Opcode::LDI(a),
+            Opcode::JMP,
+            Opcode::RET,
+            Opcode::LDI(3),
+            Opcode::RET
+        ]
+    );
+    assert_eq!(code(&fc, "E"), vec![Opcode::JMP, Opcode::RET]);
+    // Syscalls don't use the return stack, so they are left as calls
+    assert_eq!(
+        &code(&fc, "F")[1..],
+        &[Opcode::LDI(0), Opcode::CALL, Opcode::RET]
+    );
+}
+
+#[test]
+fn test_tail_call_run() {
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+
+    fc.execute_string(
+        ": A 1 ; : B A ; : D DUP IF A EXIT THEN 3 ; ' B EXECUTE : E EXECUTE ; ' A E 0 D 1 D",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 1, 0, 3, 1, 1]);
+}
+
+#[test]
+fn test_tail_recursion_deep() {
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+
+    fc.execute_string(
+        ": COUNTDOWN DUP IF 1- RECURSE THEN ; 100000 COUNTDOWN",
+        GasLimit::Unlimited,
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+}
+
+#[test]
+fn test_tail_recursion_constant_return_stack() {
+    let mut fc = ForthCompiler::default();
+    fc.set_backtraces(true);
+    fc.set_optimize(true);
+
+    // Fails at the bottom of 10000 calls deep, with only the first call left on the return stack
+    match fc.execute_string(
+        ": DOWN DUP 0= IF 1 0 DIV THEN 1- RECURSE ; 10000 DOWN",
+        GasLimit::Unlimited,
+    ) {
+        Err(ForthError::DivisionByZero { .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    let frames = &fc.last_backtrace().unwrap().frames;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].word.as_deref(), Some("DOWN"));
+    assert_eq!(frames[1].word, None);
+}
+
+#[test]
+fn test_mutual_tail_recursion_deep() {
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+
+    fc.execute_string(
+        "FORWARD ODD? : EVEN? DUP 0= IF DROP -1 EXIT THEN 1- ODD? ; : ODD? DUP 0= IF DROP 0 EXIT THEN 1- EVEN? ; 100001 EVEN? 100001 ODD?",
+        GasLimit::Unlimited,
+    )
+    .unwrap();
+
+    assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, -1]);
+}
+
+#[test]
+fn test_tail_call_needs_optimize() {
+    let source = ": A 1 ; : B A ; : DOWN DUP 0= IF 1 0 DIV THEN 1- RECURSE ; 100 DOWN";
+    let mut plain = ForthCompiler::default();
+    let mut optimized = ForthCompiler::default();
+    plain.set_backtraces(true);
+    optimized.set_backtraces(true);
+    optimized.set_optimize(true);
+
+    for fc in [&mut plain, &mut optimized] {
+        match fc.execute_string(source, GasLimit::Unlimited) {
+            Err(ForthError::DivisionByZero { .. }) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    // Without -O every call stays a call, so the backtrace has every word that was running
+    assert!(plain.disassemble("B").unwrap().contains("CALL A"));
+    assert_eq!(plain.last_backtrace().unwrap().frames.len(), 102);
+    assert!(optimized.disassemble("B").unwrap().contains("JMP A"));
+    assert_eq!(optimized.last_backtrace().unwrap().frames.len(), 2);
+}
+
+#[test]
+fn test_tail_call_inlined() {
+    let mut fc = ForthCompiler::default();
+    fc.set_optimize(true);
+
+    fc.execute_string(
+        ": A 1 ; : B A ; INLINE : C B B ADD ; C",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    // B's tail jump to A goes back to being a call when B is copied into C
+    let a = fc.word_addresses["A"] as i64;
+    let start = fc.word_addresses["C"];
+    assert_eq!(
+        &fc.sm.st.opcodes[start..start + 6],
+        &[
+            Opcode::LDI(a),
+            Opcode::CALL,
+            Opcode::LDI(a),
+            Opcode::CALL,
+            Opcode::ADD,
+            Opcode::RET
+        ]
+    );
+    assert_eq!(&fc.sm.st.number_stack, &vec![2_i64]);
+}
+
+#[test]
+fn test_disassemble() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(": SQUARE DUP MUL ;", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(
+        fc.disassemble("SQUARE").unwrap(),
+        ": SQUARE\n     3  DUP\n     4  MUL\n     5  RET\n;\n"
+    );
+}
+
+#[test]
+fn test_disassemble_calls_and_jumps() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": SQUARE DUP MUL ; : F DUP IF SQUARE ELSE 7 . THEN SQUARE ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(
+        fc.disassemble("F").unwrap(),
+        [
+            ": F",
+            "     6  DUP",
+            "     7  JRZ -> 13",
+            "     9  CALL SQUARE",
+            "    11  JR -> 17",
+            "    13  LDI 7",
+            "    14  SYSCALL Dot",
+            "    17  CALL SQUARE",
+            "    19  RET",
+            ";",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn test_disassemble_recurse_and_loops() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": F 3 0 DO I DUP IF RECURSE THEN LOOP ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(
+        fc.disassemble("F").unwrap(),
+        [
+            ": F",
+            "     3  LDI 3",
+            "     4  LDI 0",
+            "     5  PUSHLP",
+            "     6  GETLP",
+            "     7  DUP",
+            "     8  JRZ -> 12",
+            "    10  CALL F",
+            "    12  INCLP",
+            "    13  CMPLOOP",
+            "    14  JRZ -> 6",
+            "    16  DROPLP",
+            "    17  RET",
+            ";",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn test_disassemble_unknown() {
+    let fc = ForthCompiler::default();
+
+    match fc.disassemble("NOTHING") {
+        Err(ForthError::UnknownToken(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    match fc.disassemble("DUP") {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_see() {
+    let mut fc = ForthCompiler::default();
+    let output = SharedOutput::default();
+    fc.set_output(output.clone());
+
+    fc.execute_string(": A 1 ; : B A 2 ; SEE B", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(
+        output.contents(),
+        ": B\n     5  CALL A\n     7  LDI 2\n     8  RET\n;\n"
+    );
+    assert_eq!(&fc.sm.st.number_stack, &Vec::<i64>::new());
+}
+
+#[test]
+fn test_see_after_output() {
+    let mut fc = ForthCompiler::default();
+    let output = SharedOutput::default();
+    fc.set_output(output.clone());
+
+    fc.execute_string(": F 2 ; 1 . SEE F 3 .", GasLimit::Limited(100))
+        .unwrap();
+
+    assert_eq!(
+        output.contents(),
+        format!(
+            "1 : F\n{:6}  LDI 2\n{:6}  RET\n;\n3 ",
+            FIRST_WORD_ADDRESS,
+            FIRST_WORD_ADDRESS + 1
+        )
+    );
+}
+
+#[test]
+fn test_see_fail() {
+    let mut fc = ForthCompiler::default();
+    fc.set_output(SharedOutput::default());
+
+    match fc.execute_string("SEE NOTHING", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    match fc.execute_string("SEE", GasLimit::Limited(100)) {
+        Err(ForthError::InvalidSyntax(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+fn effect(inputs: usize, outputs: usize) -> Option<StackEffect> {
+    Some(StackEffect { inputs, outputs })
+}
+
+#[test]
+fn test_stack_effect_inferred() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": SQUARE DUP MUL ; : TWO 1 2 ; : GONE DROP ; : QUAD SQUARE SQUARE ; : SPIN ROT ; \
+         : ABS2 DUP 0< IF NEGATE THEN ; : SUM 0 SWAP 0 DO I ADD LOOP ; : HI .\" hi\" ; : P . ; \
+         : SIGN CASE 0 OF 0 ENDOF -1 OF -1 ENDOF 1 SWAP ENDCASE ; : FIRST 10 0 DO I LEAVE LOOP ; \
+         : CLAMP DUP 10 > IF DROP 10 EXIT THEN ; VARIABLE V 5 CONSTANT C : STORE V ! ; : NOTE ( just a note ) 1 ;",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(fc.stack_effect("SQUARE"), effect(1, 1));
+    assert_eq!(fc.stack_effect("TWO"), effect(0, 2));
+    assert_eq!(fc.stack_effect("GONE"), effect(1, 0));
+    assert_eq!(fc.stack_effect("QUAD"), effect(1, 1));
+    assert_eq!(fc.stack_effect("SPIN"), effect(3, 3));
+    assert_eq!(fc.stack_effect("ABS2"), effect(1, 1));
+    assert_eq!(fc.stack_effect("SUM"), effect(1, 1));
+    assert_eq!(fc.stack_effect("HI"), effect(0, 0));
+    assert_eq!(fc.stack_effect("P"), effect(1, 0));
+    assert_eq!(fc.stack_effect("SIGN"), effect(1, 1));
+    assert_eq!(fc.stack_effect("FIRST"), effect(0, 1));
+    assert_eq!(fc.stack_effect("CLAMP"), effect(1, 1));
+    assert_eq!(fc.stack_effect("V"), effect(0, 1));
+    assert_eq!(fc.stack_effect("C"), effect(0, 1));
+    assert_eq!(fc.stack_effect("STORE"), effect(1, 0));
+    assert_eq!(fc.stack_effect("NOTE"), effect(0, 1));
+}
+
+#[test]
+fn test_stack_effect_unknown() {
+    let mut fc = ForthCompiler::default();
+
+    fc.execute_string(
+        ": RUN EXECUTE ; : MAYBE ?DUP ; : FOREVER BEGIN AGAIN ; : FACT DUP 1 > IF DUP 1- RECURSE MUL THEN ; \
+         DEFER D : CALLS-D D ; FORWARD LATER : CALLS-LATER LATER ;",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    for word in [
+        "RUN",
+        "MAYBE",
+        "FOREVER",
+        "FACT",
+        "D",
+        "CALLS-D",
+        "CALLS-LATER",
+    ] {
+        assert_eq!(fc.stack_effect(word), None, "{}", word);
+    }
+}
+
+#[test]
+fn test_stack_effect_declared() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+
+    fc.execute_string(
+        ": SQUARE ( n -- n*n ) DUP MUL ; : KEEP2 ( a b -- a b ) ; : RUN ( xt -- n ) EXECUTE ; \
+         : FACT ( n -- n! ) DUP 1 > IF DUP 1- RECURSE MUL THEN ; : ONE 1 ; ' ONE RUN 5 FACT 3 SQUARE",
+        GasLimit::Limited(1000),
+    )
+    .unwrap();
+
+    assert_eq!(fc.stack_effect("SQUARE"), effect(1, 1));
+    // The code needs nothing, but callers have to go by what was declared
+    assert_eq!(fc.stack_effect("KEEP2"), effect(2, 2));
+    // There is no knowing what EXECUTE does, so the comment is taken at its word
+    assert_eq!(fc.stack_effect("RUN"), effect(1, 1));
+    assert_eq!(fc.stack_effect("FACT"), effect(1, 1));
+    assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 120, 9]);
+}
+
+#[test]
+fn test_stack_effect_extra_item() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+    let source = ": SQUARE ( n -- n*n )\n  DUP DUP MUL ;";
+
+    let err = fc
+        .execute_string(source, GasLimit::Limited(100))
+        .unwrap_err();
+    let location = fc.last_error_location().unwrap();
+    assert_eq!((location.line, location.column), (1, 10));
+    assert_eq!(location.word.as_deref(), Some("SQUARE"));
+    assert_eq!(
+        err.to_string(),
+        "SQUARE is declared ( 1 -- 1 ), but its code does ( 1 -- 2 )"
+    );
+    match err {
+        ForthError::StackEffectMismatch {
+            word,
+            declared,
+            actual,
+        } => {
+            assert_eq!(word, "SQUARE");
+            assert_eq!(Some(declared), effect(1, 1));
+            assert_eq!(actual, effect(1, 2));
+        }
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+
+    // The word doesn't get defined
+    match fc.execute_string("SQUARE", GasLimit::Limited(100)) {
+        Err(ForthError::UnknownToken(_)) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_stack_effect_too_many_inputs() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+
+    match fc.execute_string(": F ( a b -- c ) ADD ADD ;", GasLimit::Limited(100)) {
+        Err(ForthError::StackEffectMismatch { actual, .. }) => assert_eq!(actual, effect(3, 1)),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_stack_effect_paths_differ() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+
+    match fc.execute_string(": F ( flag -- ) IF 1 THEN ;", GasLimit::Limited(100)) {
+        Err(ForthError::StackEffectMismatch { actual: None, .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    match fc.execute_string(": G ( -- ) 5 0 DO I LOOP ;", GasLimit::Limited(100)) {
+        Err(ForthError::StackEffectMismatch { actual: None, .. }) => (),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_stack_effect_of_words_called() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+
+    fc.execute_string(
+        ": SQUARE DUP MUL ; : RUN ( xt -- n ) EXECUTE ; : ONE 1 ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    match fc.execute_string(": F ( n -- ) SQUARE ;", GasLimit::Limited(100)) {
+        Err(ForthError::StackEffectMismatch { actual, .. }) => assert_eq!(actual, effect(1, 1)),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    match fc.execute_string(": G ( -- ) ['] ONE RUN ;", GasLimit::Limited(100)) {
+        Err(ForthError::StackEffectMismatch { actual, .. }) => assert_eq!(actual, effect(0, 1)),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+    fc.execute_string(
+        ": H ( n -- n ) SQUARE ['] ONE RUN ADD ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_stack_effect_optimized_and_inlined() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+    fc.set_optimize(true);
+    fc.set_inline_threshold(8);
+
+    fc.execute_string(
+        ": SQUARE ( n -- n*n ) DUP MUL ; : F ( n -- n ) 2 3 ADD DROP SQUARE 1+ ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    match fc.execute_string(": G ( n -- ) SQUARE 1 2 ADD ;", GasLimit::Limited(100)) {
+        Err(ForthError::StackEffectMismatch { actual, .. }) => assert_eq!(actual, effect(1, 2)),
+        r => panic!("Incorrect error type returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_stack_effect_image() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+    fc.execute_string(": RUN ( xt -- n ) EXECUTE ;", GasLimit::Limited(100))
+        .unwrap();
+    let mut image = Vec::new();
+    fc.save_image(&mut image).unwrap();
+
+    let mut loaded = ForthCompiler::default();
+    loaded.load_image(image.as_slice()).unwrap();
+    assert_eq!(loaded.stack_effect("RUN"), effect(1, 1));
+}
+
+#[test]
+fn test_definition_is_open() {
+    assert!(!definition_is_open(""));
+    assert!(!definition_is_open("1 2 ADD"));
+    assert!(definition_is_open(": SQUARE"));
+    assert!(definition_is_open(": SQUARE\nDUP MUL\n"));
+    assert!(!definition_is_open(": SQUARE\nDUP MUL ;\n"));
+    assert!(definition_is_open(": SQUARE DUP MUL ; : CUBE DUP"));
+    // A ; inside a string or remark doesn't close anything
+    assert!(definition_is_open(": HI .\" ; \" ( ; ) "));
+}
+
+#[test]
+fn test_stack_effect_off_by_default() {
+    let mut fc = ForthCompiler::default();
+
+    // Comments are just comments until checking is turned on
+    fc.execute_string(
+        ": SQUARE ( n -- n*n ) DUP DUP MUL ; : W ( see FOO -- ) 1 ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    // What callers get is worked out from the code
+    assert_eq!(fc.stack_effect("SQUARE"), effect(1, 2));
+    assert_eq!(fc.stack_effect("W"), effect(0, 1));
+}
+
+#[test]
+fn test_stack_effect_prose_comment() {
+    let mut fc = ForthCompiler::default();
+    fc.set_check_stack_effects(true);
+
+    fc.execute_string(
+        ": A ( counts down -- see B, which does the rest ) 1 ; : B ( \"--\" marks a comment ) 2 ; \
+         : C ( a -- b -- c ) 3 ; : D ( x -- 0 | x x ) ?DUP ;",
+        GasLimit::Limited(100),
+    )
+    .unwrap();
+
+    assert_eq!(fc.stack_effect("A"), effect(0, 1));
+    assert_eq!(fc.stack_effect("B"), effect(0, 1));
+    assert_eq!(fc.stack_effect("C"), effect(0, 1));
+    assert_eq!(fc.stack_effect("D"), None);
+}